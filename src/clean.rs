@@ -0,0 +1,192 @@
+use std::io::{stdin, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::config::{SessionConfig, LOCAL_SCHEME};
+use crate::plan::{Plan, PlannedChange};
+use crate::remote::remote_exec::RemoteExecError;
+use crate::shell::ShellCommand;
+use crate::state::{StateError, SyncState};
+use crate::transport::connect_or_exit;
+
+#[derive(Debug, Error)]
+pub enum CleanError {
+    #[error("Refusing to clean {0:?}: {1}")]
+    UnsafeRoot(String, &'static str),
+    #[error("Refusing to clean without confirmation, pass --yes to skip it")]
+    NotConfirmed,
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Remote(#[from] RemoteExecError),
+    #[error(transparent)]
+    State(#[from] StateError),
+}
+
+// characters which the remote shell would interpret in an unquoted path
+const SHELL_SPECIAL: &str = " \t\n'\"\\$`*?[]{}()<>|&;#!";
+
+/// Check that the remote root is a directory which is safe to delete from:
+/// not the filesystem root, a top-level directory or a home directory,
+/// and a plain path which means the same quoted or not
+pub fn check_root(root: &str) -> Result<(), &'static str> {
+    let root = root.strip_prefix(LOCAL_SCHEME).unwrap_or(root);
+    if root.trim().is_empty() {
+        return Err("the remote root is empty");
+    }
+    if root.contains(|c| SHELL_SPECIAL.contains(c)) {
+        return Err("the remote root contains spaces or shell special characters");
+    }
+    if root.split('/').any(|part| part == "." || part == "..") {
+        return Err("the remote root contains . or .. components");
+    }
+
+    let parts: Vec<&str> = root.split('/').filter(|part| !part.is_empty()).collect();
+    match (root.starts_with('/'), parts.as_slice()) {
+        (true, []) => Err("the remote root is the filesystem root"),
+        (true, [_]) => Err("the remote root is a top-level directory"),
+        (true, ["home" | "Users", _]) => Err("the remote root is a home directory"),
+        (false, [home]) if home.starts_with('~') => Err("the remote root is a home directory"),
+        _ => Ok(()),
+    }
+}
+
+/// Delete the files dirsync synced to the remote, or everything in the remote root with `all`.
+/// Unless `yes` is given, the user has to confirm first.
+pub fn clean(config: &SessionConfig, yes: bool, all: bool) -> Result<(), CleanError> {
+    check_root(&config.remote.root)
+        .map_err(|reason| CleanError::UnsafeRoot(config.remote.root.clone(), reason))?;
+    let mut transport = connect_or_exit(config);
+
+    let destination = config.destination();
+    let state = SyncState::load(config)?;
+    let files: Vec<PathBuf> = state.files.keys().cloned().collect();
+    if config.dry_run {
+        let paths = match all {
+            true => transport.list_paths()?,
+            false => files.into_iter().collect(),
+        };
+        let mut plan = Plan::default();
+        for path in paths {
+            plan.insert(path, PlannedChange::Delete);
+        }
+        plan.print(&format!("Dry run: cleaning {destination}"));
+        return Ok(());
+    }
+    if !all && files.is_empty() {
+        println!("Nothing has been synced to {destination}, pass --all to delete everything in it");
+        return Ok(());
+    }
+
+    if !yes {
+        // the shell resolves ~ and relative roots
+        let resolved = transport.exec(&ShellCommand::new("pwd"))?;
+        let resolved = match resolved.trim() {
+            "" => config.remote.root.as_str(),
+            resolved => resolved,
+        };
+        let deleting = match all {
+            true => {
+                let du = transport.exec(
+                    &ShellCommand::new("du")
+                        .args(["-sk", "."])
+                        .raw("2>/dev/null"),
+                )?;
+                let kib: u64 = du
+                    .split_whitespace()
+                    .next()
+                    .and_then(|kib| kib.parse().ok())
+                    .unwrap_or(0);
+                format!("everything in it ({})", format_size(kib * 1024))
+            }
+            false => {
+                let size = state.files.values().map(|version| version.size).sum();
+                format!(
+                    "the {} files synced by dirsync ({})",
+                    files.len(),
+                    format_size(size)
+                )
+            }
+        };
+        println!("Cleaning {destination}");
+        println!("  resolved path: {resolved}");
+        println!("  deleting: {deleting}");
+        confirm("Continue?")?;
+    }
+
+    match all {
+        true => transport.delete(Path::new(""))?,
+        false => transport.remove_files(&files)?,
+    }
+    // nothing is synced anymore
+    SyncState::remove(&config.local_root)?;
+    Ok(())
+}
+
+/// Ask the user to confirm on the terminal
+fn confirm(prompt: &str) -> Result<(), CleanError> {
+    if !stdin().is_terminal() {
+        return Err(CleanError::NotConfirmed);
+    }
+    let mut stderr = std::io::stderr();
+    let _ = write!(stderr, "{prompt} [y/N] ");
+    let _ = stderr.flush();
+
+    let mut line = String::new();
+    let _ = stdin().lock().read_line(&mut line);
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(CleanError::Cancelled),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_roots() {
+        assert!(check_root("/srv/app").is_ok());
+        assert!(check_root("/home/user/project").is_ok());
+        assert!(check_root("~/project").is_ok());
+        assert!(check_root("path/to/root").is_ok());
+        assert!(check_root("local:///tmp/target").is_ok());
+    }
+
+    #[test]
+    fn unsafe_roots() {
+        for root in [
+            "",
+            " ",
+            "/",
+            "/srv",
+            "/home/user",
+            "/Users/user/",
+            "~",
+            "~/",
+            "~user",
+            "/srv/../etc",
+            "./project",
+            "/srv/my app",
+            "/srv/$HOME",
+            "local:///",
+        ] {
+            assert!(check_root(root).is_err(), "{root:?} should be refused");
+        }
+    }
+}
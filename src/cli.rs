@@ -1,17 +1,32 @@
 use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+use crate::config::{TargetKind, WatcherKind};
+use crate::remote::auth::AuthMethod;
 use crate::remote::cli::RemoteSubcommand;
+use crate::remote::known_hosts::HostKeyPolicy;
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum SubCommand {
     #[command(arg_required_else_help = true, name = "init")]
     #[command(about = "Initialize dirsync for a directory")]
+    #[command(disable_help_flag = true)]
     Init(RemoteConfigRecord),
 
     #[command(name = "clean")]
-    #[command(about = "Delete the contents of the remote directory")]
-    Clean,
+    #[command(about = "Delete the files dirsync synced to the remote directory")]
+    Clean {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Delete everything in the remote directory, not only the synced files
+        #[arg(short, long)]
+        all: bool,
+    },
+
+    #[command(name = "status")]
+    #[command(about = "Show the local changes which have not been synced yet")]
+    Status,
 
     #[command(name = "remote")]
     #[command(about = "Tools for working with the remote")]
@@ -26,6 +41,14 @@ pub enum SubCommand {
         #[arg(short, long)]
         root: String,
 
+        /// How to detect changes
+        #[arg(long, value_enum, default_value_t = WatcherKind::Native)]
+        watcher: WatcherKind,
+
+        /// How often the poll watcher scans the paths, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        poll_interval_ms: u64,
+
         #[arg(trailing_var_arg = true)]
         roots: Vec<String>,
     },
@@ -51,6 +74,12 @@ More information can be found here: https://github.com/spencerkohan/dirsync
 pub struct CliOptions {
     // The locaal root directory to be synchronized
     pub source: Option<String>,
+    /// Print what would be synced or deleted, without changing anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Print more detail about what the session is doing
+    #[arg(long, global = true)]
+    pub verbose: bool,
     // Initialize the .dirsync directory
     #[command(subcommand)]
     pub subcommand: Option<SubCommand>,
@@ -63,17 +92,87 @@ pub struct ReceivePathRec {
 
 #[derive(Debug, Clone, Deserialize, Serialize, Args)]
 pub struct RemoteConfigRecord {
-    /// The remote root of the sync directory
+    /// The remote root of the sync directory, or local:///path for a directory on this machine
     #[arg(short, long)]
     pub root: String,
 
-    /// The remote host
+    /// Where the root is: on a host over ssh (the default), on this machine, or in a docker container
+    #[arg(short, long, value_enum)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<TargetKind>,
+
+    /// The docker container, for docker targets
     #[arg(short, long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+
+    /// The remote host (only needed for ssh targets)
+    #[arg(short, long, default_value = "")]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub host: String,
 
-    /// The remote user
+    /// The remote user (defaults to the user from the ssh config)
+    #[arg(short, long)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// The ssh port on the remote host (defaults to 22)
+    #[arg(short = 'P', long)]
+    #[serde(
+        default,
+        deserialize_with = "crate::config::deserialize_port",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub port: Option<u16>,
+
+    /// The identity file used to authenticate with the remote host
     #[arg(short, long)]
-    pub user: String,
+    #[serde(
+        alias = "identityFile",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub identity_file: Option<String>,
+
+    /// How the host key of the remote host is verified against ~/.ssh/known_hosts
+    #[arg(long, value_enum)]
+    #[serde(
+        alias = "strictHostKeyChecking",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub strict_host_key_checking: Option<HostKeyPolicy>,
+
+    /// Jump hosts to connect through, as `user@host:port` (like `ssh -J`)
+    #[arg(short = 'J', long, value_delimiter = ',')]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump: Option<Vec<String>>,
+
+    /// The authentication methods to try, in order
+    /// (defaults to agent,key,keyboard-interactive,password)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Vec<AuthMethod>>,
+
+    /// Whether dirsync may prompt for passphrases and passwords on the terminal
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<bool>,
+
+    /// Seconds between ssh keepalive messages, or 0 to disable them
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval: Option<u32>,
+
+    /// Seconds to wait when connecting, and for remote commands to respond, or 0 to wait forever
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+
+    /// Print help (`-h` is used for the host)
+    #[arg(long, action = clap::ArgAction::Help)]
+    #[serde(skip)]
+    help: Option<bool>,
 
     #[clap(skip)]
     pub receive_paths: Option<Vec<ReceivePathRec>>,
@@ -1,37 +1,163 @@
 use crate::cli::RemoteConfigRecord;
-use serde::{Deserialize, Serialize};
+use crate::remote::host::{expand_path, Hop, ResolvedHost};
+use crate::shell::quote;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
 use crate::cli::CliOptions;
 
+/// The prefix of a `remote.root` which is a directory on this machine
+pub const LOCAL_SCHEME: &str = "local://";
+
+/// The exit status with which `dirsync watch` rejects options it doesn't know
+pub const USAGE_ERROR: i32 = 2;
+
 fn default_as_true() -> bool {
     true
 }
 
+fn default_debounce_ms() -> u64 {
+    50
+}
+
+fn default_max_batch_ms() -> u64 {
+    1000
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+/// The ssh port may be written either as a number or as a string
+/// (i.e. `port = 22` or `port = "22"`)
+pub fn deserialize_port<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PortRec {
+        Number(u16),
+        String(String),
+    }
+
+    match Option::<PortRec>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(PortRec::Number(port)) => Ok(Some(port)),
+        Some(PortRec::String(port)) => port
+            .trim()
+            .parse::<u16>()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid port: {port}"))),
+    }
+}
+
+/// How changes are synced between the local root and the remote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    /// Local changes are pushed to the remote (and receive paths are pulled)
+    #[default]
+    Push,
+    /// Changes on either side are synced to the other
+    Bidirectional,
+}
+
+/// How a file changed on both sides is resolved in bidirectional mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    LocalWins,
+    RemoteWins,
+    NewestWins,
+    /// Keep the local version next to the remote one, with a `.conflict` suffix
+    #[default]
+    KeepBoth,
+}
+
+/// How files are transferred to and from the remote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportKind {
+    /// Run the local rsync binary, over ssh
+    #[default]
+    Rsync,
+    /// Transfer files over dirsync's own ssh session, without any external tools
+    Sftp,
+}
+
+/// Where the remote root is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetKind {
+    /// A remote host, over ssh
+    #[default]
+    Ssh,
+    /// A directory on this machine
+    Local,
+    /// A running docker container, through `docker cp` and `docker exec`
+    Docker,
+}
+
+/// How local changes are detected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatcherKind {
+    /// File system events from the OS, e.g. inotify
+    #[default]
+    Native,
+    /// Scan the tree at an interval, which also sees changes on network filesystems
+    Poll,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     #[serde(alias = "ignoreGitignore", default = "default_as_true")]
     pub ignore_gitignore: bool,
+    // Mirror local deletions and renames to the remote
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default)]
+    pub mode: SyncMode,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+    #[serde(default)]
+    pub transport: TransportKind,
+    // How long to wait for more changes before syncing, in milliseconds
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    // How long a change may wait for a sync while more changes keep coming, in milliseconds
+    #[serde(default = "default_max_batch_ms")]
+    pub max_batch_ms: u64,
+    // Stop a running onSyncDidFinish action when there are new changes to sync
+    #[serde(default)]
+    pub cancel_stale_actions: bool,
+    #[serde(default)]
+    pub watcher: WatcherKind,
+    // How often the poll watcher scans the tree, in milliseconds
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
     pub remote: RemoteConfigRecord,
 }
 
 impl Config {
     pub fn new(remote: RemoteConfigRecord) -> Config {
-        return Config {
+        Config {
             ignore_gitignore: true,
+            delete: false,
+            mode: SyncMode::default(),
+            conflict: ConflictPolicy::default(),
+            transport: TransportKind::default(),
+            debounce_ms: default_debounce_ms(),
+            max_batch_ms: default_max_batch_ms(),
+            cancel_stale_actions: false,
+            watcher: WatcherKind::default(),
+            poll_interval_ms: default_poll_interval_ms(),
             remote,
-        };
-    }
-}
-
-impl RemoteConfigRecord {
-    fn host_string(&self) -> String {
-        let mut s: String = String::new();
-        let host = &format!("{}@{}", &self.user.clone(), &self.host.clone());
-        s.push_str(host);
-        return s;
+        }
     }
 }
 
@@ -40,7 +166,21 @@ pub struct SessionConfig {
     // The root directory to sync to the remote
     pub local_root: String,
     pub remote: RemoteConfigRecord,
+    // The remote host, resolved through the user's ssh config
+    pub host: ResolvedHost,
     pub ignore_gitignore: bool,
+    pub delete: bool,
+    pub mode: SyncMode,
+    pub conflict: ConflictPolicy,
+    pub transport: TransportKind,
+    pub debounce: Duration,
+    pub max_batch: Duration,
+    pub cancel_stale_actions: bool,
+    pub watcher: WatcherKind,
+    pub poll_interval: Duration,
+    // Only report what would change
+    pub dry_run: bool,
+    pub verbose: bool,
 }
 
 #[derive(Error, Debug)]
@@ -51,14 +191,90 @@ pub enum ReadSessionConfigError {
     FailedToRead(String),
     #[error("Failed to deserialize config file: {0}")]
     FailedToDeserialzie(String),
+    #[error("remote.host is required for ssh targets")]
+    MissingHost,
+    #[error("remote.container is required for docker targets")]
+    MissingContainer,
 }
 
 impl SessionConfig {
-    pub fn host_port_string(&self) -> String {
-        let mut s: String = String::new();
-        let host = &format!("{}:22", &self.remote.host.clone());
-        s.push_str(host);
-        return s;
+    pub fn port(&self) -> u16 {
+        self.host.port
+    }
+
+    /// Seconds between ssh keepalive messages (0 disables keepalives)
+    pub fn keepalive_interval(&self) -> u32 {
+        self.remote.keepalive_interval.unwrap_or(30)
+    }
+
+    /// The connect and read timeout for ssh sessions
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.remote.timeout.unwrap_or(30) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        }
+    }
+
+    /// The identity file set explicitly in the configuration.
+    /// Relative paths are resolved against the working directory, like `ssh -i`.
+    pub fn identity_file(&self) -> Option<PathBuf> {
+        let file = self.remote.identity_file.as_ref()?;
+        Some(expand_path(file, false))
+    }
+
+    /// The remote itself, as the last hop of the connection
+    pub fn target_hop(&self) -> Hop {
+        Hop {
+            hostname: self.host.hostname.clone(),
+            port: self.port(),
+            user: self.host.user.clone(),
+            identity_file: self.identity_file().or(self.host.identity_file.clone()),
+        }
+    }
+
+    /// The remote shell rsync should use to reach the remote host,
+    /// passed as `rsync -e <shell>`.
+    /// Only options which override the ssh config are passed, so that rsync
+    /// resolves the host alias the same way as the ssh2 session.
+    pub fn rsync_shell(&self) -> Option<String> {
+        let mut args: Vec<String> = Vec::new();
+        if let Some(port) = self.remote.port {
+            args.push(format!("-p {port}"));
+        }
+        if let Some(identity_file) = self.identity_file() {
+            args.push(format!("-i {}", quote(&identity_file.to_string_lossy())));
+        }
+        if let Some(jump) = &self.remote.jump {
+            args.push(format!("-J {}", jump.join(",")));
+        }
+        if args.is_empty() {
+            return None;
+        }
+        Some(format!("ssh {}", args.join(" ")))
+    }
+
+    /// True if the path (relative to the root) is inside one of the receive paths
+    pub fn is_in_receive_path(&self, path: &Path) -> bool {
+        let Some(receive_paths) = &self.remote.receive_paths else {
+            return false;
+        };
+        receive_paths
+            .iter()
+            .any(|receive_path| path.starts_with(receive_path.path.trim_matches('/')))
+    }
+
+    /// The options for `dirsync watch` on the target, so that it uses the same watcher.
+    /// A client which predates them exits with `USAGE_ERROR`, and is run again without them.
+    pub fn watch_args(&self) -> Vec<String> {
+        match self.watcher {
+            WatcherKind::Native => Vec::new(),
+            WatcherKind::Poll => vec![
+                String::from("--watcher"),
+                String::from("poll"),
+                String::from("--poll-interval-ms"),
+                self.poll_interval.as_millis().to_string(),
+            ],
+        }
     }
 
     pub fn exclude_path(&self) -> PathBuf {
@@ -66,20 +282,53 @@ impl SessionConfig {
         path.push(self.local_root.clone());
         path.push(".dirsync");
         path.push("ignore");
-        return path;
+        path
+    }
+
+    /// The configured target kind.
+    /// A `local://` root is always a local target.
+    pub fn target_kind(&self) -> TargetKind {
+        if self.remote.root.starts_with(LOCAL_SCHEME) {
+            return TargetKind::Local;
+        }
+        self.remote.kind.unwrap_or_default()
+    }
+
+    /// The directory to sync to, for local targets
+    pub fn local_target(&self) -> Option<PathBuf> {
+        if self.target_kind() != TargetKind::Local {
+            return None;
+        }
+        let path = self.remote.root.trim_start_matches(LOCAL_SCHEME);
+        Some(expand_path(path, false))
     }
 
     pub fn destination(&self) -> String {
-        let mut s: String = String::new();
-        s.push_str(&self.remote.host_string().as_str());
-        s.push_str(":");
-        s.push_str(self.remote.root.clone().as_str());
-        return s;
+        match self.target_kind() {
+            TargetKind::Ssh => {
+                let mut s: String = String::new();
+                s.push_str(&format!("{}@{}", &self.host.user, &self.host.alias));
+                s.push(':');
+                s.push_str(self.remote.root.clone().as_str());
+                s
+            }
+            TargetKind::Local => self
+                .local_target()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            // the same syntax as `docker cp`
+            TargetKind::Docker => format!(
+                "{}:{}",
+                self.remote.container.as_deref().unwrap_or_default(),
+                self.remote.root
+            ),
+        }
     }
 
-    pub fn with_local_root(local_root: &String) -> Result<SessionConfig, ReadSessionConfigError> {
+    pub fn with_local_root(local_root: &str) -> Result<SessionConfig, ReadSessionConfigError> {
         let mut config_path = PathBuf::new();
-        config_path.push(local_root.clone());
+        config_path.push(local_root);
         config_path.push(".dirsync");
         config_path.push("config.toml");
 
@@ -97,15 +346,44 @@ impl SessionConfig {
             Err(err) => return Err(ReadSessionConfigError::FailedToDeserialzie(err.to_string())),
         };
 
-        return Ok(SessionConfig {
-            local_root: local_root.clone(),
+        let kind = match config.remote.root.starts_with(LOCAL_SCHEME) {
+            true => TargetKind::Local,
+            false => config.remote.kind.unwrap_or_default(),
+        };
+        match kind {
+            TargetKind::Ssh if config.remote.host.is_empty() => {
+                return Err(ReadSessionConfigError::MissingHost)
+            }
+            TargetKind::Docker if config.remote.container.is_none() => {
+                return Err(ReadSessionConfigError::MissingContainer)
+            }
+            _ => {}
+        }
+
+        Ok(SessionConfig {
+            local_root: local_root.to_string(),
+            host: ResolvedHost::resolve(&config.remote),
             remote: config.remote,
             ignore_gitignore: config.ignore_gitignore,
-        });
+            delete: config.delete,
+            mode: config.mode,
+            conflict: config.conflict,
+            transport: config.transport,
+            debounce: Duration::from_millis(config.debounce_ms),
+            max_batch: Duration::from_millis(config.max_batch_ms),
+            cancel_stale_actions: config.cancel_stale_actions,
+            watcher: config.watcher,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            dry_run: false,
+            verbose: false,
+        })
     }
 
     pub fn get(args: CliOptions) -> Result<SessionConfig, ReadSessionConfigError> {
         let local_root = args.source.unwrap_or(".".to_string());
-        SessionConfig::with_local_root(&local_root)
+        let mut config = SessionConfig::with_local_root(&local_root)?;
+        config.dry_run = args.dry_run;
+        config.verbose = args.verbose;
+        Ok(config)
     }
 }
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Files smaller than this are always transferred whole
+pub const MIN_DELTA_SIZE: u64 = 1 << 20;
+
+/// Block checksums of a file, used to find the parts of a new version
+/// which the remote already has
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Signature {
+    pub block_size: u32,
+    /// The rolling checksum and the strong hash of each block
+    pub blocks: Vec<(u32, u64)>,
+}
+
+/// A step in building the new version of a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// A block of the old version
+    Copy { block: usize },
+    /// A range of the new version which has to be transferred
+    Literal { offset: u64, len: u64 },
+}
+
+/// The block size for a file: larger files get larger blocks,
+/// so that the number of blocks stays reasonable
+pub fn block_size(file_size: u64) -> u32 {
+    let target = (file_size / 1024).next_power_of_two();
+    target.clamp(4 * 1024, 1024 * 1024) as u32
+}
+
+/// The rsync rolling checksum of a block
+fn weak_checksum(data: &[u8]) -> u32 {
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    let len = data.len() as u32;
+    for (i, byte) in data.iter().enumerate() {
+        a = a.wrapping_add(*byte as u32);
+        b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+    }
+    (a & 0xffff) | (b << 16)
+}
+
+/// Move the window of a rolling checksum one byte forward
+fn roll(checksum: u32, block_size: u32, out: u8, into: u8) -> u32 {
+    let a = (checksum & 0xffff)
+        .wrapping_sub(out as u32)
+        .wrapping_add(into as u32);
+    let b = (checksum >> 16)
+        .wrapping_sub(block_size.wrapping_mul(out as u32))
+        .wrapping_add(a);
+    (a & 0xffff) | (b << 16)
+}
+
+/// FNV-1a, to confirm blocks with matching rolling checksums
+fn strong_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn signature(path: &Path) -> std::io::Result<Signature> {
+    let mut file = File::open(path)?;
+    let block_size = block_size(file.metadata()?.len());
+    let mut blocks = Vec::new();
+    let mut buffer = vec![0; block_size as usize];
+    loop {
+        let n = read_full(&mut file, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        blocks.push((weak_checksum(&buffer[..n]), strong_hash(&buffer[..n])));
+        if n < buffer.len() {
+            break;
+        }
+    }
+    Ok(Signature { block_size, blocks })
+}
+
+fn read_full(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// The steps to turn the old version of a file, described by its signature,
+/// into the new version at `path`
+pub fn diff(old: &Signature, path: &Path) -> std::io::Result<Vec<Op>> {
+    let block_size = old.block_size as usize;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (block, (weak, _)) in old.blocks.iter().enumerate() {
+        index.entry(*weak).or_default().push(block);
+    }
+
+    let mut file = File::open(path)?;
+    let mut ops = Vec::new();
+    // the file is read in chunks, `base` is the file offset of `data[0]`
+    let mut data: Vec<u8> = Vec::new();
+    let mut base: u64 = 0;
+    let mut pos: usize = 0;
+    let mut literal_start: u64 = 0;
+    let mut eof = false;
+    let mut checksum: Option<u32> = None;
+    let mut chunk = vec![0; 4 * block_size.max(64 * 1024)];
+
+    loop {
+        // keep at least one full block after the window start
+        while !eof && data.len() - pos < block_size + 1 {
+            if pos > chunk.len() {
+                data.drain(..pos);
+                base += pos as u64;
+                pos = 0;
+            }
+            match file.read(&mut chunk)? {
+                0 => eof = true,
+                n => data.extend_from_slice(&chunk[..n]),
+            }
+        }
+        if data.len() - pos < block_size {
+            break;
+        }
+
+        let window = &data[pos..pos + block_size];
+        let weak = *checksum.get_or_insert_with(|| weak_checksum(window));
+        let matched = index.get(&weak).and_then(|blocks| {
+            let strong = strong_hash(window);
+            blocks
+                .iter()
+                .find(|block| old.blocks[**block].1 == strong)
+                .copied()
+        });
+
+        match matched {
+            Some(block) => {
+                let offset = base + pos as u64;
+                if offset > literal_start {
+                    ops.push(Op::Literal {
+                        offset: literal_start,
+                        len: offset - literal_start,
+                    });
+                }
+                ops.push(Op::Copy { block });
+                pos += block_size;
+                literal_start = base + pos as u64;
+                checksum = None;
+            }
+            None if data.len() - pos > block_size => {
+                checksum = Some(roll(
+                    weak,
+                    old.block_size,
+                    data[pos],
+                    data[pos + block_size],
+                ));
+                pos += 1;
+            }
+            None => break,
+        }
+    }
+
+    let end = base + data.len() as u64;
+    if end > literal_start {
+        ops.push(Op::Literal {
+            offset: literal_start,
+            len: end - literal_start,
+        });
+    }
+    Ok(ops)
+}
+
+/// The number of bytes which have to be transferred for a diff
+pub fn literal_size(ops: &[Op]) -> u64 {
+    ops.iter()
+        .map(|op| match op {
+            Op::Literal { len, .. } => *len,
+            Op::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+/// True if every copied block stays at its offset, so the diff can be applied
+/// by overwriting the changed ranges of the old file in place
+pub fn is_in_place(ops: &[Op], block_size: u32) -> bool {
+    let mut offset: u64 = 0;
+    for op in ops {
+        match op {
+            Op::Copy { block } => {
+                if *block as u64 * block_size as u64 != offset {
+                    return false;
+                }
+                offset += block_size as u64;
+            }
+            Op::Literal { len, .. } => offset += len,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const BLOCK: usize = 4096;
+
+    /// Data without repeating blocks
+    fn data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn write(name: &str, data: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dirsync-delta-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn diff_of(name: &str, old: &[u8], new: &[u8]) -> (Signature, Vec<Op>) {
+        let old_path = write(&format!("{name}-old"), old);
+        let new_path = write(&format!("{name}-new"), new);
+        let signature = signature(&old_path).unwrap();
+        let ops = diff(&signature, &new_path).unwrap();
+        let _ = std::fs::remove_file(old_path);
+        let _ = std::fs::remove_file(new_path);
+        (signature, ops)
+    }
+
+    #[test]
+    fn rolling_checksum() {
+        let data = data(2 * BLOCK);
+        let size = BLOCK as u32;
+        let mut checksum = weak_checksum(&data[..BLOCK]);
+        for start in 1..BLOCK {
+            checksum = roll(checksum, size, data[start - 1], data[start + BLOCK - 1]);
+            assert_eq!(checksum, weak_checksum(&data[start..start + BLOCK]));
+        }
+    }
+
+    #[test]
+    fn unchanged_file() {
+        let old = data(3 * BLOCK);
+        let (signature, ops) = diff_of("unchanged", &old, &old);
+        assert_eq!(signature.block_size, BLOCK as u32);
+        assert_eq!(
+            ops,
+            vec![
+                Op::Copy { block: 0 },
+                Op::Copy { block: 1 },
+                Op::Copy { block: 2 }
+            ]
+        );
+        assert_eq!(literal_size(&ops), 0);
+        assert!(is_in_place(&ops, signature.block_size));
+    }
+
+    #[test]
+    fn changed_block() {
+        let old = data(3 * BLOCK);
+        let mut new = old.clone();
+        new[BLOCK + 10] ^= 0xff;
+        let (signature, ops) = diff_of("changed", &old, &new);
+        assert_eq!(
+            ops,
+            vec![
+                Op::Copy { block: 0 },
+                Op::Literal {
+                    offset: BLOCK as u64,
+                    len: BLOCK as u64
+                },
+                Op::Copy { block: 2 }
+            ]
+        );
+        assert!(is_in_place(&ops, signature.block_size));
+    }
+
+    #[test]
+    fn inserted_bytes() {
+        let old = data(3 * BLOCK);
+        let new = [b"abc".as_slice(), &old, b"de"].concat();
+        let (signature, ops) = diff_of("inserted", &old, &new);
+        assert_eq!(
+            ops,
+            vec![
+                Op::Literal { offset: 0, len: 3 },
+                Op::Copy { block: 0 },
+                Op::Copy { block: 1 },
+                Op::Copy { block: 2 },
+                Op::Literal {
+                    offset: 3 + 3 * BLOCK as u64,
+                    len: 2
+                }
+            ]
+        );
+        assert_eq!(literal_size(&ops), 5);
+        // the blocks moved, so the old file can't be patched in place
+        assert!(!is_in_place(&ops, signature.block_size));
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(block_size(0), 4 * 1024);
+        assert_eq!(block_size(64 << 20), 64 * 1024);
+        assert_eq!(block_size(1 << 40), 1024 * 1024);
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how long after a transfer events on the transferred paths are still treated as echoes
+const ECHO_WINDOW: Duration = Duration::from_secs(2);
+
+/// Paths recently written by dirsync itself, by a pull or a push.
+///
+/// The watchers see those writes as well, but syncing them back to the
+/// other side would just start a ping-pong between the two sides.
+#[derive(Debug, Clone, Default)]
+pub struct EchoGuard {
+    paths: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+}
+
+impl EchoGuard {
+    /// Mark paths which are about to be written by a transfer
+    pub fn begin(&self, paths: &[PathBuf]) {
+        // suppress events for as long as the transfer takes
+        self.suppress(paths, Instant::now() + Duration::from_secs(24 * 60 * 60));
+    }
+
+    /// Mark paths for which a transfer has finished
+    pub fn finish(&self, paths: &[PathBuf]) {
+        self.suppress(paths, Instant::now() + ECHO_WINDOW);
+    }
+
+    fn suppress(&self, paths: &[PathBuf], until: Instant) {
+        let mut suppressed = self.paths.lock().unwrap();
+        for path in paths {
+            suppressed.insert(path.clone(), until);
+        }
+    }
+
+    /// True if an event on this path (relative to the root) was caused by a transfer
+    pub fn is_echo(&self, path: &Path) -> bool {
+        let now = Instant::now();
+        let mut suppressed = self.paths.lock().unwrap();
+        suppressed.retain(|_, until| *until > now);
+        // directories are transferred recursively
+        path.ancestors()
+            .any(|ancestor| suppressed.contains_key(ancestor))
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{SessionConfig, SyncMode};
+
+/// The paths which are excluded from the sync.
+///
+/// Patterns follow the `.gitignore` syntax.  They are read from `.git/info/exclude`,
+/// the `.gitignore` file in each directory and `.dirsync/ignore`, in increasing order
+/// of precedence, relative to the local root.  `.git` is always ignored.  Transfers through
+/// rsync get the same rules as filters, so that the watcher and every transfer agree on
+/// what is ignored.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    gitignore: bool,
+    // rules from .git/info/exclude, which the .gitignore files take precedence over
+    excludes: Vec<Rule>,
+    // rules from .dirsync/ignore and the receive paths, which take precedence over everything
+    overrides: Vec<Rule>,
+    // the rules of the .gitignore file in each directory which has been looked at
+    gitignores: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    // the directory of the file the rule is from, relative to the root
+    base: String,
+    pattern: String,
+    // the pattern is matched against the path below the base, instead of the file name
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Clone for IgnoreRules {
+    fn clone(&self) -> IgnoreRules {
+        IgnoreRules {
+            root: self.root.clone(),
+            gitignore: self.gitignore,
+            excludes: self.excludes.clone(),
+            overrides: self.overrides.clone(),
+            gitignores: Mutex::new(self.gitignores.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl IgnoreRules {
+    pub fn new(config: &SessionConfig) -> IgnoreRules {
+        let root = PathBuf::from(&config.local_root);
+        let mut rules = IgnoreRules {
+            gitignore: config.ignore_gitignore,
+            ..Default::default()
+        };
+        if config.ignore_gitignore {
+            rules.excludes = read_rules(&root.join(".git/info/exclude"), "");
+        }
+        rules.overrides = read_rules(&config.exclude_path(), "");
+        rules.root = root;
+
+        // receive paths are only ever pulled, in push mode
+        if let (SyncMode::Push, Some(paths)) = (config.mode, &config.remote.receive_paths) {
+            for path in paths {
+                let pattern = format!("/{}", path.path.trim_matches('/'));
+                rules.overrides.extend(parse_rule(&pattern, ""));
+            }
+        }
+        // last, so that no other rule can include it
+        rules.overrides.extend(parse_rule(".git", ""));
+        rules
+    }
+
+    /// True if the path (relative to the root) is a file which rules are read from
+    pub fn is_rules_file(path: &Path) -> bool {
+        path.file_name().is_some_and(|name| name == ".gitignore")
+            || path == Path::new(".git/info/exclude")
+            || path == Path::new(".dirsync/ignore")
+    }
+
+    /// The rules of the .gitignore file in a directory (relative to the root)
+    fn gitignore(&self, dir: &Path) -> Arc<Vec<Rule>> {
+        if !self.gitignore {
+            return Arc::default();
+        }
+        let mut gitignores = self.gitignores.lock().unwrap();
+        let rules = gitignores.entry(dir.to_path_buf()).or_insert_with(|| {
+            let path = self.root.join(dir).join(".gitignore");
+            Arc::new(read_rules(&path, &dir.to_string_lossy()))
+        });
+        Arc::clone(rules)
+    }
+
+    /// True if a path (relative to the root) is ignored,
+    /// either itself or because one of its parent directories is
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<String> = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let mut dir = PathBuf::new();
+        let mut gitignores = Vec::new();
+        for (n, name) in components.iter().enumerate() {
+            // the .gitignore files of the parent directories apply, the deepest one last
+            gitignores.push(self.gitignore(&dir));
+            let rules = self
+                .excludes
+                .iter()
+                .chain(gitignores.iter().flat_map(|rules| rules.iter()))
+                .chain(&self.overrides);
+            let prefix = components[..=n].join("/");
+            if matches(rules, &prefix, name, n + 1 < components.len() || is_dir) {
+                return true;
+            }
+            dir.push(name);
+        }
+        false
+    }
+
+    /// The rules as rsync filter arguments, for a transfer of the given paths (relative to the root).
+    /// rsync uses the first rule which matches, so they are listed from the highest precedence down.
+    pub fn rsync_filters<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) -> Vec<String> {
+        // the .gitignore files in the parent directories of the paths, and below them
+        let mut dirs = BTreeSet::new();
+        for path in paths {
+            dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            self.collect_gitignore_dirs(path, &mut dirs);
+        }
+        let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+        dirs.sort_by_key(|dir| dir.components().count());
+
+        let gitignores: Vec<Arc<Vec<Rule>>> = dirs.iter().map(|dir| self.gitignore(dir)).collect();
+        let rules: Vec<&Rule> = self
+            .excludes
+            .iter()
+            .chain(gitignores.iter().flat_map(|rules| rules.iter()))
+            .chain(&self.overrides)
+            .collect();
+        rules
+            .into_iter()
+            .rev()
+            .flat_map(Rule::rsync_filters)
+            .collect()
+    }
+
+    /// Add the directories at or below `dir` which have a .gitignore file,
+    /// leaving out ignored directories
+    fn collect_gitignore_dirs(&self, dir: &Path, dirs: &mut BTreeSet<PathBuf>) {
+        if !self.gitignore {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(self.root.join(dir)) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let child = dir.join(&name);
+            if name == ".gitignore" {
+                dirs.insert(dir.to_path_buf());
+            } else if entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                && !self.is_ignored(&child, true)
+            {
+                self.collect_gitignore_dirs(&child, dirs);
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// The rsync filters which match the same paths as the rule
+    fn rsync_filters(&self) -> Vec<String> {
+        let base = match self.base.is_empty() {
+            true => String::from("/"),
+            false => format!("/{}/", self.base),
+        };
+        // a leading **/ also matches no directories at all, which rsync's ** doesn't
+        let patterns = match (self.anchored, self.pattern.strip_prefix("**/")) {
+            (true, Some(rest)) => vec![format!("{base}{rest}"), format!("{base}**/{rest}")],
+            (true, None) => vec![format!("{base}{}", self.pattern)],
+            (false, _) if self.base.is_empty() => vec![self.pattern.clone()],
+            (false, _) => vec![
+                format!("{base}{}", self.pattern),
+                format!("{base}**/{}", self.pattern),
+            ],
+        };
+        let action = match self.negated {
+            true => '+',
+            false => '-',
+        };
+        let suffix = match self.dir_only {
+            true => "/",
+            false => "",
+        };
+        patterns
+            .into_iter()
+            .map(|pattern| format!("--filter={action} {pattern}{suffix}"))
+            .collect()
+    }
+}
+
+fn read_rules(path: &Path, base: &str) -> Vec<Rule> {
+    match fs::read_to_string(path) {
+        Ok(source) => source
+            .lines()
+            .filter_map(|line| parse_rule(line, base))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn parse_rule(line: &str, base: &str) -> Option<Rule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negated, pattern) = match line.strip_prefix('!') {
+        Some(pattern) => (true, pattern),
+        None => (false, line),
+    };
+    let (dir_only, pattern) = match pattern.strip_suffix('/') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let anchored = pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+    if pattern.is_empty() {
+        return None;
+    }
+    Some(Rule {
+        base: base.to_string(),
+        pattern: pattern.to_string(),
+        anchored,
+        dir_only,
+        negated,
+    })
+}
+
+/// Whether the rules ignore a path, given with its file name
+fn matches<'a>(
+    rules: impl Iterator<Item = &'a Rule>,
+    path: &str,
+    name: &str,
+    is_dir: bool,
+) -> bool {
+    // the last matching rule wins, so negations can re-include a path
+    let mut ignored = false;
+    for rule in rules {
+        if rule.dir_only && !is_dir {
+            continue;
+        }
+        let text = match (rule.anchored, rule.base.is_empty()) {
+            (false, _) => name,
+            (true, true) => path,
+            (true, false) => {
+                let below = path
+                    .strip_prefix(rule.base.as_str())
+                    .and_then(|path| path.strip_prefix('/'));
+                match below {
+                    Some(below) => below,
+                    None => continue,
+                }
+            }
+        };
+        if glob(rule.pattern.as_bytes(), text.as_bytes()) {
+            ignored = !rule.negated;
+        }
+    }
+    ignored
+}
+
+/// Match a path against a glob pattern.
+/// `*` and `?` don't match `/`, while `**` matches any number of directories.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.starts_with(b"**/") => {
+            // zero or more leading directories
+            glob(&pattern[3..], text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == b'/' && glob(&pattern[3..], &text[i + 1..]))
+        }
+        Some(b'*') if pattern.starts_with(b"**") => {
+            (0..=text.len()).any(|i| glob(&pattern[2..], &text[i..]))
+        }
+        Some(b'*') => {
+            let max = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=max).any(|i| glob(&pattern[1..], &text[i..]))
+        }
+        Some(b'?') => match text.first() {
+            Some(c) if *c != b'/' => glob(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => {
+            let Some(c) = text.first() else {
+                return false;
+            };
+            match match_class(&pattern[1..], *c) {
+                Some((true, rest)) if *c != b'/' => glob(rest, &text[1..]),
+                Some(_) => false,
+                // an unterminated class is a literal '['
+                None => *c == b'[' && glob(&pattern[1..], &text[1..]),
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob(&pattern[2..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Match a character against a `[...]` class, starting after the `[`.
+/// Returns whether it matched, and the rest of the pattern after the `]`.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut i) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == b']' && !first {
+            return Some((matched != negated, &pattern[i + 1..]));
+        }
+        first = false;
+        if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            matched |= pattern[i] <= c && c <= pattern[i + 2];
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(source: &str) -> IgnoreRules {
+        IgnoreRules {
+            overrides: source
+                .lines()
+                .filter_map(|line| parse_rule(line, ""))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*.rs", b"main.rs"));
+        assert!(!glob(b"*.rs", b"src/main.rs"));
+        assert!(glob(b"src/*.rs", b"src/main.rs"));
+        assert!(glob(b"**/main.rs", b"main.rs"));
+        assert!(glob(b"**/main.rs", b"src/bin/main.rs"));
+        assert!(glob(b"src/**", b"src/bin/main.rs"));
+        assert!(glob(b"a?c", b"abc"));
+        assert!(!glob(b"a?c", b"a/c"));
+        assert!(glob(b"\\*", b"*"));
+        assert!(!glob(b"\\*", b"a"));
+        assert!(glob(b"file[0-9]", b"file7"));
+        assert!(!glob(b"file[!0-9]", b"file7"));
+        // an unterminated class is a literal [
+        assert!(glob(b"a[b", b"a[b"));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(
+            match_class(b"abc]rest", b'b'),
+            Some((true, b"rest".as_slice()))
+        );
+        assert_eq!(match_class(b"a-c]", b'd'), Some((false, b"".as_slice())));
+        assert_eq!(match_class(b"^a-c]", b'd'), Some((true, b"".as_slice())));
+        // a leading ] is part of the class
+        assert_eq!(match_class(b"]]", b']'), Some((true, b"".as_slice())));
+        assert_eq!(match_class(b"abc", b'a'), None);
+    }
+
+    #[test]
+    fn ignored_paths() {
+        let rules = rules("target/\n*.log\n!keep.log\n/build\ndocs/*.tmp");
+        assert!(rules.is_ignored(Path::new("target"), true));
+        assert!(!rules.is_ignored(Path::new("target"), false));
+        assert!(rules.is_ignored(Path::new("target/debug/dirsync"), false));
+        assert!(rules.is_ignored(Path::new("sub/target/x"), false));
+        assert!(rules.is_ignored(Path::new("logs/out.log"), false));
+        assert!(!rules.is_ignored(Path::new("keep.log"), false));
+        assert!(rules.is_ignored(Path::new("build"), false));
+        assert!(!rules.is_ignored(Path::new("src/build"), false));
+        assert!(rules.is_ignored(Path::new("docs/a.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("docs/sub/a.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("src/main.rs"), false));
+    }
+
+    #[test]
+    fn filters() {
+        let rules = rules("target/\n!keep.log\n/build\n**/out");
+        assert_eq!(
+            rules.rsync_filters([Path::new("")]),
+            vec![
+                "--filter=- /out",
+                "--filter=- /**/out",
+                "--filter=- /build",
+                "--filter=+ keep.log",
+                "--filter=- target/",
+            ]
+        );
+    }
+}
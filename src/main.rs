@@ -1,156 +1,377 @@
+mod clean;
 mod cli;
 mod config;
+mod delta;
+mod echo;
+mod ignore;
 mod init;
+mod plan;
+mod reconcile;
 mod remote;
+mod shell;
+mod state;
+mod sync;
+mod transfer_set;
+mod transport;
 mod watch;
 
 extern crate notify;
 
 use crate::cli::CliOptions;
 use crate::cli::SubCommand;
-use crate::config::SessionConfig;
-use crate::remote::Remote;
+use crate::config::{SessionConfig, SyncMode};
+use crate::echo::EchoGuard;
+use crate::ignore::IgnoreRules;
+use crate::reconcile::reconcile;
+use crate::remote::connection::Connection;
+use crate::state::{LocalChange, SyncState};
+use crate::sync::ChangeSet;
+use crate::transport::{connect_or_exit, Transport};
+use crate::watch::{event_paths, relative_path, Debouncer, FileWatcher};
 use clap::Parser;
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
-use remote::receive_from_remote::watch_remote_receivable_paths;
-use std::path::Path;
+use notify::DebouncedEvent;
+use remote::receive_from_remote::{watch_remote_receivable_paths, watch_remote_root};
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::thread::sleep;
 use std::time::Duration;
-use std::vec::Vec;
 
-// Perform rsync from source to destination
-fn sync(config: &config::SessionConfig) {
-    use std::process::Command;
-
-    fn rsync(source: &str, destinatin: &str, args: &Vec<String>) {
-        println!("executing rsync: {} {}", source, destinatin);
-
-        let mut rsync = Command::new("rsync")
-            .arg("-v") // verbose output
-            .arg("-a") // archived: we use this to only sync files which have changed
-            .arg("-r") // recursive
-            .args(args)
-            .arg(source)
-            .arg(destinatin)
-            .spawn()
-            .expect("failed to execute rsync");
-
-        let result = rsync.wait().expect("failed to wait for process");
-
-        println!("rsync finished");
-        assert!(result.success());
-    }
-
-    // we sync actions explicitly here, since they might be ignored otherwise
-    let dirsync_dir_local = &format!("{}/.dirsync", &config.local_root);
-    let dirsync_dir_remote = &format!("{}", &config.destination());
-    rsync(dirsync_dir_local, dirsync_dir_remote, &Vec::new());
-
-    let exclude_gitignore = config.ignore_gitignore && Path::new(".gitignore").exists();
-    let exclude_file = Path::new(config.exclude_path().to_str().unwrap()).exists();
-
-    let mut args: Vec<String> = Vec::new();
-    if exclude_gitignore {
-        args.push(String::from("--exclude-from=.gitignore"));
-    }
-    if exclude_file {
-        args.push(String::from(format!(
-            "--exclude-from={}",
-            config.exclude_path().to_str().unwrap()
-        )));
-    }
-
-    // exclude remote receive paths
-    if let Some(paths) = &config.remote.receive_paths {
-        for path in paths {
-            args.push(String::from(format!("--exclude={}", path.path)));
-        }
-    }
-
-    rsync(&config.local_root, &config.destination(), &args)
-}
+/// How long to wait before retrying a sync which failed while the connection was up
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 fn filter(event: DebouncedEvent) -> Option<DebouncedEvent> {
     match event {
         DebouncedEvent::NoticeWrite(_) => None,
         DebouncedEvent::NoticeRemove(_) => None,
-        DebouncedEvent::Rescan => None,
         DebouncedEvent::Error(_, _) => None,
         _ => Some(event),
     }
 }
 
 fn start_watch_thread(
-    root: String,
+    config: &SessionConfig,
     flush_signal: Sender<()>,
-    events: &mut Arc<Mutex<Vec<DebouncedEvent>>>,
+    changes: &mut Arc<Mutex<ChangeSet>>,
+    echo: EchoGuard,
 ) {
-    let events = Arc::clone(events);
+    let changes = Arc::clone(changes);
+    let config = config.clone();
+    let root = config.local_root.clone();
     thread::spawn(move || {
         // Create a channel to receive watcher events.
         let (tx, rx) = channel();
-        let mut watcher = watcher(tx, Duration::from_millis(20)).unwrap();
-        watcher.watch(root, RecursiveMode::Recursive).unwrap();
+        let _watcher = FileWatcher::start(
+            config.watcher,
+            config.poll_interval,
+            &[PathBuf::from(&root)],
+            tx,
+        )
+        .unwrap();
+        let mut ignore = IgnoreRules::new(&config);
+        // a burst of events is synced at once, when it ends
+        let mut debouncer = Debouncer::new(config.debounce, config.max_batch);
 
         loop {
-            match rx.recv() {
+            let received = match debouncer.remaining() {
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                // the batch has to end even while events keep coming
+                Some(remaining) if remaining.is_zero() => Err(RecvTimeoutError::Timeout),
+                Some(remaining) => rx.recv_timeout(remaining),
+            };
+            match received {
                 Ok(event) => {
                     println!("handling event: {:?}", event);
                     match filter(event) {
                         Some(event) => {
-                            let signal = flush_signal.clone();
-                            let mut events_vec = events.lock().unwrap();
-                            events_vec.push(event);
-                            thread::spawn(move || {
-                                sleep(Duration::from_millis(20));
-                                signal.send(()).unwrap();
-                            });
+                            if event_paths(&event).into_iter().any(|path| {
+                                relative_path(Path::new(&root), path)
+                                    .is_some_and(|path| IgnoreRules::is_rules_file(&path))
+                            }) {
+                                ignore = IgnoreRules::new(&config);
+                            }
+                            // ignored and receive paths are not synced, writes from
+                            // reconcile pulls are echoes, and the sync state is written by
+                            // dirsync itself
+                            let skip = |path: &Path| {
+                                ignore.is_ignored(path, Path::new(&root).join(path).is_dir())
+                                    || (config.mode == SyncMode::Push
+                                        && config.is_in_receive_path(path))
+                                    || echo.is_echo(path)
+                                    || SyncState::is_state_path(path)
+                            };
+                            let mut changes = changes.lock().unwrap();
+                            changes.add_event(Path::new(&root), &event, &skip);
+                            debouncer.event();
                         }
                         None => println!("ignoring event"),
                     };
                 }
-                Err(e) => println!("watch error: {:?}", e),
+                Err(RecvTimeoutError::Timeout) => {
+                    debouncer.finish();
+                    if flush_signal.send(()).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    eprintln!("watch error: the file watcher stopped");
+                    break;
+                }
             }
         }
     });
 }
 
+/// The local changes since the last sync.
+/// Receive paths are left out in push mode, since they are only ever pulled.
+fn local_changes(config: &SessionConfig, state: &SyncState) -> BTreeMap<PathBuf, LocalChange> {
+    let mut changes = state.local_changes(&config.local_root, &IgnoreRules::new(config));
+    if config.mode == SyncMode::Push {
+        changes.retain(|path, _| !config.is_in_receive_path(path));
+    }
+    changes
+}
+
+/// Record the pushed versions of the changed paths in the manifest
+fn record_pushed(config: &SessionConfig, state: &mut SyncState, changes: &ChangeSet) {
+    let root = PathBuf::new();
+    let ignore = IgnoreRules::new(config);
+    match changes.is_full() {
+        true => state.record_local(&config.local_root, iter::once(&root), &ignore),
+        false => state.record_local(&config.local_root, changes.paths().iter(), &ignore),
+    }
+    if let Err(err) = state.save(&config.local_root) {
+        eprintln!("Warning: failed to save the sync state: {err}");
+    }
+}
+
+/// Sync the recorded changes, and run the onSyncDidFinish action.
+/// Changes recorded meanwhile are left for the next flush.
+/// Returns false if the sync failed, in which case the changes are queued again.
 fn flush_events(
     config: &SessionConfig,
-    remote: &mut Remote,
-    events: &mut Arc<Mutex<Vec<DebouncedEvent>>>,
-) {
-    let mut events_vec = events.lock().unwrap();
-    if !events_vec.is_empty() {
-        events_vec.clear();
-        sync(&config);
+    connection: &mut Connection,
+    changes: &Mutex<ChangeSet>,
+    state: &mut SyncState,
+    echo: &EchoGuard,
+) -> bool {
+    // the changes are taken out, so that the watcher isn't blocked during the sync
+    let pending = mem::take(&mut *changes.lock().unwrap());
+    if pending.is_empty() {
+        // only ignored paths changed, so there is nothing to sync
+        if pending.skipped() > 0 && config.verbose {
+            println!("Dropped a sync of {} ignored paths", pending.skipped());
+        }
+        return true;
+    }
+    let result = match (config.mode, connection.transport()) {
+        (SyncMode::Push, Some(transport)) => transport
+            .push_changes(config, &pending, state)
+            .map(|_| {
+                record_pushed(config, state, &pending);
+                true
+            })
+            .map_err(|err| err.to_string()),
+        (SyncMode::Bidirectional, Some(transport)) => {
+            reconcile(config, transport, state, &pending, echo).map_err(|err| err.to_string())
+        }
+        // keep the changes queued until the connection is back
+        (_, None) => {
+            changes.lock().unwrap().merge(pending);
+            return false;
+        }
+    };
+    let pushed = match result {
+        Ok(pushed) => pushed,
+        Err(err) => {
+            eprintln!("Sync failed: {err}");
+            // keep the changes queued, to retry once the connection is back or after a delay
+            connection.check();
+            changes.lock().unwrap().merge(pending);
+            return false;
+        }
+    };
+    if pushed {
         println!("Executing onSyncDidFinish action");
-        remote.execute_if_exists("onSyncDidFinish");
+        // the action is outdated once there are new changes to sync
+        let superseded = || config.cancel_stale_actions && !changes.lock().unwrap().is_empty();
+        connection.execute_if_exists("onSyncDidFinish", &superseded);
+    }
+    true
+}
+
+/// The changes to sync at startup, which is always the whole tree.
+/// The manifest only knows about local changes, so in push mode a full sync
+/// also repairs files changed or deleted on the remote since the last session.
+/// In bidirectional mode the whole tree is reconciled, to find remote changes as well.
+fn startup_changes() -> ChangeSet {
+    let mut changes = ChangeSet::default();
+    changes.set_full();
+    changes
+}
+
+fn load_state_or_exit(config: &SessionConfig) -> SyncState {
+    match SyncState::load(config) {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!(
+                "Delete {} to sync everything again",
+                SyncState::path(&config.local_root).display()
+            );
+            exit(1);
+        }
     }
 }
 
 fn start_main_loop(config: &SessionConfig) {
     println!("config: {:?}", config);
 
-    sync(&config);
-    let mut remote = Remote::connect(config);
-    remote.execute_if_exists("onSessionDidStart");
+    let mut state = load_state_or_exit(config);
+    let startup = startup_changes();
+    let mut transport = connect_or_exit(config);
+    if config.dry_run {
+        if let Err(err) = print_dry_run(config, transport.as_mut(), &mut state, &startup) {
+            eprintln!("Dry run failed: {err}");
+            exit(1);
+        }
+        return;
+    }
+    if config.mode == SyncMode::Push {
+        println!("Performing initial sync");
+        // a full sync includes the .dirsync directory
+        if let Err(err) = transport.push_changes(config, &startup, &state) {
+            eprintln!("Initial sync failed: {err}");
+            exit(1);
+        }
+        record_pushed(config, &mut state, &startup);
+    }
+    let mut connection = Connection::new(config, transport);
 
-    let mut events: Arc<Mutex<Vec<DebouncedEvent>>> = Arc::new(Mutex::new(vec![]));
+    let mut changes: Arc<Mutex<ChangeSet>> = Arc::new(Mutex::new(ChangeSet::default()));
+    let echo = EchoGuard::default();
+    let mut synced = true;
+    if config.mode == SyncMode::Bidirectional {
+        // a full sync would overwrite remote changes, so both sides are reconciled instead
+        *changes.lock().unwrap() = startup;
+        synced = flush_events(config, &mut connection, &changes, &mut state, &echo);
+    }
+    connection.execute_if_exists("onSessionDidStart", &|| false);
 
     // create a channel for flush events
     let (tx, rx) = channel();
-    start_watch_thread(config.local_root.clone(), tx, &mut events);
+    start_watch_thread(config, tx.clone(), &mut changes, echo.clone());
 
-    watch_remote_receivable_paths(config.clone());
+    match config.mode {
+        SyncMode::Push => watch_remote_receivable_paths(config.clone()),
+        SyncMode::Bidirectional => {
+            watch_remote_root(config.clone(), Arc::clone(&changes), tx, echo.clone())
+        }
+    }
 
     loop {
-        let _ = rx.recv();
-        flush_events(&config, &mut remote, &mut events);
+        match connection.retry_delay() {
+            None => {
+                if synced {
+                    let _ = rx.recv();
+                } else {
+                    // retry a failed sync, or sooner if there are new changes
+                    println!("Retrying the sync in {}s", SYNC_RETRY_DELAY.as_secs());
+                    let _ = rx.recv_timeout(SYNC_RETRY_DELAY);
+                }
+                // signals sent during the last sync are all handled by a single flush
+                while rx.try_recv().is_ok() {}
+                synced = flush_events(config, &mut connection, &changes, &mut state, &echo);
+            }
+            Some(delay) => {
+                // local changes are queued while the connection is down
+                let _ = rx.recv_timeout(delay);
+                if connection.reconnect() {
+                    // catch up on the changes made while the connection was down
+                    println!("Performing catch-up sync");
+                    synced = flush_events(config, &mut connection, &changes, &mut state, &echo);
+                }
+            }
+        }
+    }
+}
+
+/// Print what the sync at the start of a session would change, without changing anything
+fn print_dry_run(
+    config: &SessionConfig,
+    transport: &mut dyn Transport,
+    state: &mut SyncState,
+    startup: &ChangeSet,
+) -> Result<(), String> {
+    if config.mode == SyncMode::Bidirectional {
+        return reconcile(config, transport, state, startup, &EchoGuard::default())
+            .map(|_| ())
+            .map_err(|err| err.to_string());
+    }
+
+    let destination = config.destination();
+    // a full sync includes the .dirsync directory
+    let plan = transport
+        .push_changes(config, startup, state)
+        .map_err(|err| err.to_string())?;
+    plan.print(&format!("Dry run: pushing to {destination}"));
+
+    if let Some(receive_paths) = &config.remote.receive_paths {
+        let paths: BTreeSet<PathBuf> = receive_paths
+            .iter()
+            .map(|path| PathBuf::from(path.path.trim_matches('/')))
+            .collect();
+        transport
+            .pull(config, &paths)
+            .map_err(|err| err.to_string())?
+            .print(&format!(
+                "Dry run: pulling receive paths from {destination}"
+            ));
+    }
+    Ok(())
+}
+
+/// Print the local changes which haven't been synced yet
+fn print_status(config: &SessionConfig) {
+    let state = load_state_or_exit(config);
+    let destination = config.destination();
+    if state.is_empty() {
+        println!("Nothing has been synced to {destination} yet");
+        return;
+    }
+    let changes = local_changes(config, &state);
+    if changes.is_empty() {
+        println!("Everything is synced to {destination}");
+        return;
+    }
+    println!("Local changes not yet synced to {destination}:");
+    for (path, change) in changes {
+        let change = match change {
+            LocalChange::Added => "new",
+            LocalChange::Modified => "modified",
+            LocalChange::Deleted => "deleted",
+        };
+        println!("  {change:>8}: {}", path.display());
+    }
+}
+
+fn session_config_or_exit(opts: CliOptions) -> SessionConfig {
+    match SessionConfig::get(opts) {
+        Ok(config) => config,
+        Err(config::ReadSessionConfigError::DoesNotExist) => {
+            eprintln!("Fatal: not a dirsync directory");
+            eprintln!("There is no configuration file located at .dirsync/config.json");
+            eprintln!("To initialize this as a dirsync directory, use: `dirsync init`");
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("Error loading configuration file: {}", err);
+            exit(1);
+        }
     }
 }
 
@@ -177,55 +398,29 @@ fn main() {
                 exit(1);
             }
         },
-        Some(SubCommand::Clean) => {
-            let config = match SessionConfig::get(opts) {
-                Ok(config) => config,
-                Err(config::ReadSessionConfigError::DoesNotExist) => {
-                    eprintln!("Fatal: not a dirsync directory");
-                    eprintln!("There is no configuration file located at .dirsync/config.json");
-                    eprintln!("To initialize this as a dirsync directory, use: `dirsync init`");
-                    exit(1);
-                }
-                Err(err) => {
-                    eprintln!("Error loading configuration file: {}", err);
-                    exit(1);
-                }
-            };
-            let mut remote = remote::Remote::connect(&config);
-            remote.remove_dir(config.remote.root.as_str());
+        Some(SubCommand::Clean { yes, all }) => {
+            let config = session_config_or_exit(opts.clone());
+            if let Err(err) = clean::clean(&config, *yes, *all) {
+                eprintln!("Error cleaning the remote directory: {err}");
+                exit(1);
+            }
         }
+        Some(SubCommand::Status) => print_status(&session_config_or_exit(opts)),
         Some(SubCommand::Remote { subcommand }) => {
-            let config = match SessionConfig::get(opts.clone()) {
-                Ok(config) => config,
-                Err(config::ReadSessionConfigError::DoesNotExist) => {
-                    eprintln!("Fatal: not a dirsync directory");
-                    eprintln!("There is no configuration file located at .dirsync/config.json");
-                    eprintln!("To initialize this as a dirsync directory, use: `dirsync init`");
-                    exit(1);
-                }
-                Err(err) => {
-                    eprintln!("Error loading configuration file: {}", err);
-                    exit(1);
-                }
-            };
-            subcommand.execute(&config);
-        }
-        Some(SubCommand::Watch { root, roots }) => watch::watch_paths(root, roots),
-        _ => {
-            let config = match SessionConfig::get(opts) {
-                Ok(config) => config,
-                Err(config::ReadSessionConfigError::DoesNotExist) => {
-                    eprintln!("Fatal: not a dirsync directory");
-                    eprintln!("There is no configuration file located at .dirsync/config.json");
-                    eprintln!("To initialize this as a dirsync directory, use: `dirsync init`");
-                    exit(1);
-                }
-                Err(err) => {
-                    eprintln!("Error loading configuration file: {}", err);
-                    exit(1);
-                }
-            };
-            start_main_loop(&config);
+            let config = session_config_or_exit(opts.clone());
+            exit(subcommand.execute(&config));
         }
+        Some(SubCommand::Watch {
+            root,
+            watcher,
+            poll_interval_ms,
+            roots,
+        }) => watch::watch_paths(
+            root,
+            roots,
+            *watcher,
+            Duration::from_millis(*poll_interval_ms),
+        ),
+        _ => start_main_loop(&session_config_or_exit(opts)),
     };
 }
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A change which a sync would make, found by a dry run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlannedChange {
+    Create,
+    Update,
+    Delete,
+}
+
+impl fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            PlannedChange::Create => "would-create",
+            PlannedChange::Update => "would-update",
+            PlannedChange::Delete => "would-delete",
+        };
+        f.pad(label)
+    }
+}
+
+/// The changes found by a dry run, by path relative to the root which would be changed.
+/// Transfers which actually ran return an empty plan.
+#[derive(Debug, Default)]
+pub struct Plan {
+    changes: BTreeMap<PathBuf, PlannedChange>,
+    // the root, if it doesn't exist yet
+    root: Option<String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none() && self.changes.is_empty()
+    }
+
+    /// Record that the root itself would be created
+    pub fn create_root(&mut self, root: &str) {
+        self.root = Some(root.to_string());
+    }
+
+    pub fn insert(&mut self, path: PathBuf, change: PlannedChange) {
+        self.changes.insert(path, change);
+    }
+
+    pub fn extend(&mut self, other: Plan) {
+        self.changes.extend(other.changes);
+        self.root = self.root.take().or(other.root);
+    }
+
+    pub fn print(&self, title: &str) {
+        if self.is_empty() {
+            println!("{title}: nothing to do");
+            return;
+        }
+        println!("{title}:");
+        if let Some(root) = &self.root {
+            println!("  {:>12}: {root}", PlannedChange::Create);
+        }
+        for (path, change) in &self.changes {
+            println!("  {change:>12}: {}", path.display());
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::config::{ConflictPolicy, SessionConfig};
+use crate::echo::EchoGuard;
+use crate::ignore::IgnoreRules;
+use crate::plan::Plan;
+use crate::remote::remote_exec::RemoteExecError;
+use crate::state::{collect_local_files, is_internal, FileVersion, StateError, SyncState};
+use crate::sync::{ChangeSet, SyncError};
+use crate::transport::Transport;
+
+#[derive(Debug, Error)]
+pub enum ReconcileError {
+    #[error(transparent)]
+    Remote(#[from] RemoteExecError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    State(#[from] StateError),
+    #[error("Failed to keep the local version of {0}: {1}")]
+    KeepLocal(String, std::io::Error),
+}
+
+/// Reconcile the paths in the change set (from either side) in bidirectional mode.
+/// Returns true if anything was pushed to the remote.
+/// The paths written on either side are registered with the echo guard.
+///
+/// Each side's version of a file is compared with its version in the manifest:
+/// a file changed on one side is copied to the other, and a file changed on
+/// both sides is a conflict, resolved by the configured policy.
+pub fn reconcile(
+    config: &SessionConfig,
+    remote: &mut dyn Transport,
+    state: &mut SyncState,
+    changes: &ChangeSet,
+    echo: &EchoGuard,
+) -> Result<bool, ReconcileError> {
+    let local_root = PathBuf::from(&config.local_root);
+    let requested: Vec<PathBuf> = match changes.is_full() {
+        true => vec![PathBuf::new()],
+        false => changes
+            .paths()
+            .iter()
+            .filter(|path| !is_internal(path))
+            .cloned()
+            .collect(),
+    };
+
+    // the .dirsync directory is only ever pushed, like in push mode
+    let mut push_plan = Plan::default();
+    if changes.is_full() {
+        push_plan = remote.push_dirsync_dir(config, state)?;
+    }
+    let mut to_push: BTreeSet<PathBuf> = changes
+        .paths()
+        .iter()
+        .filter(|path| path.starts_with(".dirsync") && !SyncState::is_state_path(path))
+        .cloned()
+        .collect();
+
+    // directories are expanded to the files below them, on both sides
+    // ignored paths are left alone on both sides
+    let ignore = IgnoreRules::new(config);
+    let remote_versions = remote.file_versions(&requested)?;
+    let mut paths: BTreeSet<PathBuf> = remote_versions.keys().cloned().collect();
+    for path in &requested {
+        collect_local_files(&local_root, path, &ignore, &mut paths);
+        paths.extend(state.files_below(path).cloned());
+    }
+    paths.retain(|path| !is_internal(path) && !ignore.is_ignored(path, false));
+
+    let mut to_pull = BTreeSet::new();
+    let mut settled = BTreeSet::new();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let base = state.get(&path);
+        let local_file = local_root.join(&path);
+        let remote_version = remote_versions.get(&path);
+
+        let local_changed = match base {
+            None => local_file.is_file(),
+            Some(base) => !base.matches_local(&local_file),
+        };
+        let remote_changed = match (base, remote_version) {
+            (None, remote_version) => remote_version.is_some(),
+            (Some(_), None) => true,
+            (Some(base), Some(hash)) => base.sha256 != *hash,
+        };
+
+        match (local_changed, remote_changed) {
+            (false, false) => {}
+            (true, false) => {
+                to_push.insert(path);
+            }
+            (false, true) => {
+                to_pull.insert(path);
+            }
+            (true, true) => {
+                // both sides may have made the same change
+                let identical = match (FileVersion::of_local(&local_file), remote_version) {
+                    (None, None) => true,
+                    (Some(local), Some(hash)) => local.sha256 == *hash,
+                    _ => false,
+                };
+                if identical {
+                    settled.insert(path);
+                } else {
+                    conflicts.push(path);
+                }
+            }
+        }
+    }
+
+    for path in conflicts {
+        let local_file = local_root.join(&path);
+        let local_version = FileVersion::of_local(&local_file);
+        let remote_exists = remote_versions.contains_key(&path);
+        println!("Conflict: {} was changed on both sides", path.display());
+
+        match config.conflict {
+            ConflictPolicy::LocalWins => {
+                to_push.insert(path);
+            }
+            ConflictPolicy::RemoteWins => {
+                to_pull.insert(path);
+            }
+            ConflictPolicy::NewestWins => {
+                // a deleted file is always older, and ties go to the local version
+                let local_mtime = local_version.map(|version| version.mtime);
+                let remote_mtime = match remote_exists {
+                    true => Some(remote.mtime(&path).unwrap_or(0)),
+                    false => None,
+                };
+                if local_mtime >= remote_mtime {
+                    to_push.insert(path);
+                } else {
+                    to_pull.insert(path);
+                }
+            }
+            ConflictPolicy::KeepBoth if local_version.is_some() && remote_exists => {
+                let copy = conflict_path(&local_root, &path);
+                if config.dry_run {
+                    println!("Would keep the local version as {}", copy.display());
+                    to_pull.insert(path);
+                    continue;
+                }
+                let renamed = [path.clone(), copy.clone()];
+                echo.begin(&renamed);
+                let result = fs::rename(&local_file, local_root.join(&copy));
+                echo.finish(&renamed);
+                result.map_err(|err| ReconcileError::KeepLocal(path.display().to_string(), err))?;
+                println!("Keeping the local version as {}", copy.display());
+                to_push.insert(copy);
+                to_pull.insert(path);
+            }
+            // a file deleted on one side and changed on the other is kept
+            ConflictPolicy::KeepBoth => {
+                if local_version.is_some() {
+                    to_push.insert(path);
+                } else {
+                    to_pull.insert(path);
+                }
+            }
+        }
+    }
+
+    let mut pull_plan = Plan::default();
+    if !to_pull.is_empty() {
+        println!("Pulling {} paths from the remote", to_pull.len());
+        let pulled: Vec<PathBuf> = to_pull.iter().cloned().collect();
+        echo.begin(&pulled);
+        let result = remote.pull(config, &to_pull);
+        echo.finish(&pulled);
+        pull_plan = result?;
+    }
+    if !to_push.is_empty() {
+        let pushed: Vec<PathBuf> = to_push.iter().cloned().collect();
+        echo.begin(&pushed);
+        let result = remote.push(config, &to_push, state);
+        echo.finish(&pushed);
+        push_plan.extend(result?);
+    }
+    if config.dry_run {
+        let destination = config.destination();
+        pull_plan.print(&format!("Dry run: pulling from {destination}"));
+        push_plan.print(&format!("Dry run: pushing to {destination}"));
+        return Ok(false);
+    }
+
+    // only paths which are now the same on both sides get a new base version,
+    // e.g. ignored paths are skipped by the transfer and stay unsynced
+    let synced: Vec<PathBuf> = to_pull
+        .iter()
+        .chain(&to_push)
+        .chain(&settled)
+        .filter(|path| !is_internal(path))
+        .cloned()
+        .collect();
+    if !synced.is_empty() {
+        let remote_versions = remote.file_versions(&synced)?;
+        for path in synced {
+            let local_version = FileVersion::of_local(&local_root.join(&path));
+            match (local_version, remote_versions.get(&path)) {
+                (Some(local), Some(hash)) if local.sha256 == *hash => {
+                    state.insert(&local_root, path, local);
+                }
+                (None, None) => {
+                    state.files.remove(&path);
+                }
+                _ => {}
+            }
+        }
+        state.save(&config.local_root)?;
+    }
+
+    Ok(!to_push.is_empty())
+}
+
+/// An unused path next to `path` to keep the local version of a conflicting file
+fn conflict_path(root: &Path, path: &Path) -> PathBuf {
+    let mut candidate = PathBuf::from(format!("{}.conflict", path.display()));
+    let mut n = 1;
+    while root.join(&candidate).symlink_metadata().is_ok() {
+        n += 1;
+        candidate = PathBuf::from(format!("{}.conflict.{n}", path.display()));
+    }
+    candidate
+}
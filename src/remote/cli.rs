@@ -1,6 +1,12 @@
 use clap::Subcommand;
 
-use crate::{config::SessionConfig, remote::exec_remote};
+use crate::plan::PlannedChange;
+use crate::shell::ShellCommand;
+
+use crate::{
+    config::SessionConfig,
+    remote::{exec_remote, RemoteExecError},
+};
 
 use super::Remote;
 
@@ -24,12 +30,36 @@ pub enum RemoteSubcommand {
 
 impl RemoteSubcommand {
     pub fn execute(&self, config: &SessionConfig) -> i32 {
+        if config.dry_run {
+            let destination = config.destination();
+            match self {
+                RemoteSubcommand::Exec { args } => {
+                    println!(
+                        "Dry run: would execute on {destination}: {}",
+                        args.join(" ")
+                    )
+                }
+                RemoteSubcommand::Install => {
+                    println!("Dry run: would install dirsync on {destination}, if it is missing")
+                }
+                RemoteSubcommand::Uninstall => {
+                    println!("Dry run: uninstalling from {destination}:");
+                    println!("  {:>12}: .dirsync/client", PlannedChange::Delete);
+                }
+            }
+            return 0;
+        }
         match self {
             RemoteSubcommand::Exec { args } => {
                 let command = args.join(" ");
                 println!("Executing remote command: {}", command);
-                let (output, code) = match exec_remote(&config, command.as_str()) {
+                // the command line is the user's, so it is passed to the shell as it is
+                let (output, code) = match exec_remote(config, &ShellCommand::script(&command)) {
                     Ok(result) => result,
+                    Err(RemoteExecError::Connect(err)) => {
+                        eprintln!("Error connecting to the remote: {err}");
+                        return err.exit_code();
+                    }
                     Err(err) => {
                         eprintln!("{}", err);
                         return 1;
@@ -39,16 +69,18 @@ impl RemoteSubcommand {
                 0
             }
             RemoteSubcommand::Install => {
-                if let Err(err) = Remote::connect(config).install_dirsync() {
-                    panic!("{err}");
+                if let Err(err) = Remote::connect_or_exit(config).install_dirsync() {
+                    eprintln!("{err}");
+                    return 1;
                 };
-                1
+                0
             }
             RemoteSubcommand::Uninstall => {
-                if let Err(err) = Remote::connect(config).remove_dirsync() {
-                    panic!("{err}");
+                if let Err(err) = Remote::connect_or_exit(config).remove_dirsync() {
+                    eprintln!("{err}");
+                    return 1;
                 };
-                1
+                0
             }
         }
     }
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use crate::config::SessionConfig;
+use crate::remote::remote_exec::RemoteExecError;
+use crate::transport::{self, Transport};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+enum ConnectionState {
+    Connected(Box<dyn Transport>),
+    Disconnected {
+        next_attempt: Instant,
+        backoff: Duration,
+    },
+}
+
+/// The connection to the remote for a long-running session.
+///
+/// When the connection is lost, it is re-established with exponential backoff.
+pub struct Connection {
+    config: SessionConfig,
+    state: ConnectionState,
+}
+
+impl Connection {
+    pub fn new(config: &SessionConfig, transport: Box<dyn Transport>) -> Connection {
+        Connection {
+            config: config.clone(),
+            state: ConnectionState::Connected(transport),
+        }
+    }
+
+    pub fn transport(&mut self) -> Option<&mut dyn Transport> {
+        match &mut self.state {
+            ConnectionState::Connected(transport) => Some(transport.as_mut()),
+            ConnectionState::Disconnected { .. } => None,
+        }
+    }
+
+    /// How long to wait before the next reconnection attempt,
+    /// or `None` if the connection is up
+    pub fn retry_delay(&self) -> Option<Duration> {
+        match &self.state {
+            ConnectionState::Connected(_) => None,
+            ConnectionState::Disconnected { next_attempt, .. } => {
+                Some(next_attempt.saturating_duration_since(Instant::now()))
+            }
+        }
+    }
+
+    /// Check whether the connection is still alive after an operation failed,
+    /// and mark it as disconnected if not
+    pub fn check(&mut self) {
+        if let ConnectionState::Connected(transport) = &mut self.state {
+            if !transport.is_alive() {
+                self.disconnected();
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        println!(
+            "[connection] lost connection to {}, reconnecting in {}s",
+            self.config.destination(),
+            INITIAL_BACKOFF.as_secs()
+        );
+        self.state = ConnectionState::Disconnected {
+            next_attempt: Instant::now() + INITIAL_BACKOFF,
+            backoff: INITIAL_BACKOFF,
+        };
+    }
+
+    /// Attempt to reconnect if the next attempt is due.
+    /// Returns true if the connection was re-established.
+    pub fn reconnect(&mut self) -> bool {
+        let ConnectionState::Disconnected {
+            next_attempt,
+            backoff,
+        } = self.state
+        else {
+            return false;
+        };
+        if Instant::now() < next_attempt {
+            return false;
+        }
+
+        match transport::connect(&self.config) {
+            Ok(transport) => {
+                println!("[connection] reconnected to {}", self.config.destination());
+                self.state = ConnectionState::Connected(transport);
+                true
+            }
+            Err(err) => {
+                let backoff = (backoff * 2).min(MAX_BACKOFF);
+                println!(
+                    "[connection] failed to reconnect: {err}, retrying in {}s",
+                    backoff.as_secs()
+                );
+                self.state = ConnectionState::Disconnected {
+                    next_attempt: Instant::now() + backoff,
+                    backoff,
+                };
+                false
+            }
+        }
+    }
+
+    /// Execute an action on the remote, if the connection is up.
+    /// The action is stopped once `cancelled` returns true.
+    pub fn execute_if_exists(&mut self, event: &str, cancelled: &dyn Fn() -> bool) {
+        let Some(transport) = self.transport() else {
+            return;
+        };
+        match transport.execute_if_exists(event, cancelled) {
+            Ok(()) => {}
+            Err(RemoteExecError::Cancelled(_)) => println!("Cancelled the {event} action"),
+            Err(err) => {
+                eprintln!("Error executing {event} action: {err}");
+                self.check();
+            }
+        }
+    }
+}
@@ -0,0 +1,491 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::RemoteConfigRecord;
+
+/// The remote host, as it would be resolved by `ssh` itself.
+///
+/// `remote.host` may be an alias from the user's ssh config, so the values
+/// here combine the dirsync configuration with the matching `Host` entries
+/// of `~/.ssh/config`.  Values set explicitly in config.toml take precedence.
+#[derive(Debug, Clone)]
+pub struct ResolvedHost {
+    /// The name of the host as written in config.toml
+    pub alias: String,
+    /// The actual hostname to connect to
+    pub hostname: String,
+    pub port: u16,
+    pub user: String,
+    /// An identity file taken from the ssh config, if any
+    pub identity_file: Option<PathBuf>,
+    /// The jump hosts to connect through, in order
+    pub jump: Vec<Hop>,
+}
+
+/// A host the connection goes through: one of the jump hosts, or the remote itself.
+/// Each hop is authenticated with its own user and identity file.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub hostname: String,
+    pub port: u16,
+    pub user: String,
+    pub identity_file: Option<PathBuf>,
+}
+
+impl Hop {
+    /// Resolve a `[user@]host[:port]` jump host entry, as used by `ssh -J`
+    fn resolve_jump(spec: &str) -> Hop {
+        let (user, host_port) = match spec.split_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, spec),
+        };
+        let (host, port) = split_host_port(host_port);
+        Hop::resolve(&HostOptions::lookup(host), host, port, user)
+    }
+
+    /// Resolve a host with its ssh config options.
+    /// The port and user given explicitly take precedence over the config.
+    fn resolve(options: &HostOptions, host: &str, port: Option<u16>, user: Option<String>) -> Hop {
+        let mut hop = Hop {
+            hostname: options.hostname(host),
+            port: port
+                .or(options.get("port").and_then(|port| port.parse().ok()))
+                .unwrap_or(22),
+            user: user
+                .or(options.get("user"))
+                .or(env::var("USER").ok())
+                .unwrap_or_default(),
+            identity_file: None,
+        };
+        hop.identity_file = options
+            .get("identityfile")
+            .map(|file| expand_path(&hop.expand_tokens(&file, host), false));
+        hop
+    }
+
+    /// Expand the `%` tokens which ssh accepts in `IdentityFile`
+    fn expand_tokens(&self, value: &str, alias: &str) -> String {
+        let home = env::var("HOME").unwrap_or_default();
+        let local_user = env::var("USER").unwrap_or_default();
+        let port = self.port.to_string();
+        expand_tokens(
+            value,
+            &[
+                ('d', &home),
+                ('h', &self.hostname),
+                ('n', alias),
+                ('p', &port),
+                ('r', &self.user),
+                ('u', &local_user),
+            ],
+        )
+    }
+}
+
+fn split_host_port(host_port: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = host_port.strip_prefix('[') {
+        // [address]:port
+        if let Some((host, port)) = rest.split_once(']') {
+            return (host, port.strip_prefix(':').and_then(|port| port.parse().ok()));
+        }
+    }
+    match host_port.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, port.parse().ok()),
+        _ => (host_port, None),
+    }
+}
+
+impl ResolvedHost {
+    pub fn resolve(remote: &RemoteConfigRecord) -> ResolvedHost {
+        let options = HostOptions::lookup(&remote.host);
+        let hop = Hop::resolve(&options, &remote.host, remote.port, remote.user.clone());
+        let jump_specs = match &remote.jump {
+            Some(jump) => jump.clone(),
+            None => match options.get("proxyjump") {
+                Some(jump) if !jump.eq_ignore_ascii_case("none") => {
+                    jump.split(',').map(|spec| spec.to_string()).collect()
+                }
+                _ => Vec::new(),
+            },
+        };
+
+        ResolvedHost {
+            alias: remote.host.clone(),
+            hostname: hop.hostname,
+            port: hop.port,
+            user: hop.user,
+            identity_file: hop.identity_file,
+            jump: jump_specs
+                .iter()
+                .map(|spec| Hop::resolve_jump(spec.trim()))
+                .collect(),
+        }
+    }
+}
+
+/// Expand a path from the configuration.
+///
+/// `~/` is expanded to the home directory.  If `in_ssh_dir` is set, relative
+/// paths are resolved against `~/.ssh`.
+pub fn expand_path(path: &str, in_ssh_dir: bool) -> PathBuf {
+    let home = env::var("HOME").unwrap_or_default();
+    if let Some(rest) = path.strip_prefix("~/") {
+        PathBuf::from(home).join(rest)
+    } else if PathBuf::from(path).is_absolute() || !in_ssh_dir {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(home).join(".ssh").join(path)
+    }
+}
+
+fn user_ssh_config_path() -> Option<PathBuf> {
+    let home = env::var("HOME").ok()?;
+    Some(PathBuf::from(home).join(".ssh").join("config"))
+}
+
+// how deeply `Include` directives can be nested, as in ssh
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Options for a single host, with case-insensitive keys.
+/// The first value set for a key wins.
+#[derive(Debug, Default)]
+struct HostOptions {
+    values: Vec<(String, String)>,
+}
+
+impl HostOptions {
+    /// The options for a host from the user's ssh config
+    fn lookup(host: &str) -> HostOptions {
+        let mut options = HostOptions::default();
+        if let Some(path) = user_ssh_config_path() {
+            options.read_file(&path, host, 0);
+        }
+        options
+    }
+
+    #[cfg(test)]
+    fn parse(source: &str, host: &str) -> HostOptions {
+        let mut options = HostOptions::default();
+        options.read(source, host, 0);
+        options
+    }
+
+    fn read_file(&mut self, path: &Path, host: &str, depth: usize) {
+        if let Ok(source) = fs::read_to_string(path) {
+            self.read(&source, host, depth);
+        }
+    }
+
+    /// Collect the options which apply to the host, reading the config top to bottom
+    /// like ssh does: options before the first `Host` line apply to every host,
+    /// and each `Host` or `Match` block applies if its patterns or criteria match.
+    /// Files included from a block are only read if the block applies.
+    fn read(&mut self, source: &str, host: &str, depth: usize) {
+        let mut applies = true;
+
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let split = line
+                .find(|c: char| c.is_whitespace() || c == '=')
+                .unwrap_or(line.len());
+            let key = line[..split].to_lowercase();
+            let value = line[split..]
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+                .trim()
+                .trim_matches('"');
+
+            match key.as_str() {
+                "host" => applies = host_matches(value, host),
+                "match" => applies = self.match_applies(value, host),
+                "include" if applies && depth < MAX_INCLUDE_DEPTH => {
+                    for path in include_paths(value) {
+                        self.read_file(&path, host, depth + 1);
+                    }
+                }
+                _ if applies && !value.is_empty() && self.get(&key).is_none() => {
+                    self.values.push((key, value.to_string()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// True if the criteria of a `Match` line hold for the host.
+    /// Only `all`, `host`, `originalhost`, `user` and `localuser` are supported;
+    /// blocks with other criteria, such as `exec`, never apply.
+    fn match_applies(&self, criteria: &str, host: &str) -> bool {
+        let mut words = criteria.split_whitespace();
+        while let Some(criterion) = words.next() {
+            let criterion = criterion.to_lowercase();
+            let (negated, criterion) = match criterion.strip_prefix('!') {
+                Some(criterion) => (true, criterion),
+                None => (false, criterion.as_str()),
+            };
+            if criterion == "all" {
+                continue;
+            }
+            let (Some(patterns), Some(value)) = (words.next(), self.match_value(criterion, host))
+            else {
+                return false;
+            };
+            if host_matches(patterns, &value) == negated {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The value a `Match` criterion is checked against
+    fn match_value(&self, criterion: &str, host: &str) -> Option<String> {
+        match criterion {
+            "host" => Some(self.hostname(host)),
+            "originalhost" => Some(host.to_string()),
+            "user" => self.get("user").or(env::var("USER").ok()),
+            "localuser" => env::var("USER").ok(),
+            _ => None,
+        }
+    }
+
+    fn hostname(&self, host: &str) -> String {
+        self.get("hostname")
+            .map(|hostname| expand_tokens(&hostname, &[('h', host)]))
+            .unwrap_or(host.to_string())
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+/// The files an `Include` line refers to.
+/// Relative paths are in `~/.ssh`, and `*` and `?` wildcards are expanded in sorted order.
+fn include_paths(value: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for pattern in value.split_whitespace() {
+        let pattern = expand_path(pattern.trim_matches('"'), true);
+        let mut matches = vec![PathBuf::new()];
+        for component in pattern.components() {
+            let name = component.as_os_str().to_string_lossy();
+            if !name.contains(['*', '?']) {
+                matches.iter_mut().for_each(|path| path.push(component));
+                continue;
+            }
+            let mut expanded = Vec::new();
+            for dir in &matches {
+                let Ok(entries) = fs::read_dir(dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    // like glob(3), wildcards don't match a leading dot
+                    let hidden = file_name.starts_with('.') && !name.starts_with('.');
+                    if !hidden && wildcard_match(&name, &file_name) {
+                        expanded.push(dir.join(file_name));
+                    }
+                }
+            }
+            expanded.sort();
+            matches = expanded;
+        }
+        paths.extend(matches);
+    }
+    paths
+}
+
+/// Replace the `%` tokens in an ssh config value.
+/// `%%` is a literal `%`, and unknown tokens are left as they are.
+fn expand_tokens(value: &str, tokens: &[(char, &str)]) -> String {
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(token) => match tokens.iter().find(|(name, _)| *name == token) {
+                Some((_, value)) => expanded.push_str(value),
+                None => {
+                    expanded.push('%');
+                    expanded.push(token);
+                }
+            },
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+/// True if the host matches a `Host` line or a `Match` pattern list: at least one
+/// pattern matches, and none of the negated (`!pattern`) patterns do
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let host = host.to_lowercase();
+    let mut matched = false;
+    for pattern in patterns.split(|c: char| c.is_whitespace() || c == ',') {
+        let pattern = pattern.trim_matches('"').to_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, &host) => return false,
+            Some(_) => {}
+            None => matched = matched || wildcard_match(&pattern, &host),
+        }
+    }
+    matched
+}
+
+/// Match a string against a pattern with `*` and `?` wildcards
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // the position after the last `*`, and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // let the last `*` consume one more character
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(split_host_port("example.com"), ("example.com", None));
+        assert_eq!(
+            split_host_port("example.com:2222"),
+            ("example.com", Some(2222))
+        );
+        assert_eq!(split_host_port("example.com:ssh"), ("example.com", None));
+        assert_eq!(split_host_port("::1"), ("::1", None));
+        assert_eq!(split_host_port("[::1]:2222"), ("::1", Some(2222)));
+        assert_eq!(split_host_port("[fe80::1]"), ("fe80::1", None));
+    }
+
+    #[test]
+    fn first_matching_value_wins() {
+        let source = "\
+User global
+Host dev
+  HostName dev.example.com
+  Port 2200
+Host *.example.com dev
+  Port 2222
+  IdentityFile ~/.ssh/example
+Host *
+  User fallback
+";
+        let options = HostOptions::parse(source, "dev");
+        assert_eq!(options.hostname("dev"), "dev.example.com");
+        assert_eq!(options.get("port").as_deref(), Some("2200"));
+        assert_eq!(
+            options.get("identityfile").as_deref(),
+            Some("~/.ssh/example")
+        );
+        assert_eq!(options.get("user").as_deref(), Some("global"));
+    }
+
+    #[test]
+    fn negated_patterns_and_match_blocks() {
+        let source = "\
+Match exec \"true\"
+  Port 1
+Host * !dev
+  Port 2
+Host=d?v
+  Port=3
+Match originalhost other,!dev
+  User matched
+Match !host other.example.com
+  Port 4
+";
+        let options = |host| HostOptions::parse(source, host);
+        assert_eq!(options("dev").get("port").as_deref(), Some("3"));
+        assert_eq!(options("dev").get("user"), None);
+        assert_eq!(options("other").get("port").as_deref(), Some("2"));
+        assert_eq!(options("other").get("user").as_deref(), Some("matched"));
+
+        let source = "\
+Host other
+  HostName other.example.com
+Match host *.example.com
+  Port 5
+";
+        let options = |host| HostOptions::parse(source, host);
+        assert_eq!(options("other").get("port").as_deref(), Some("5"));
+        assert_eq!(options("dev").get("port"), None);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("dirsync-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("conf.d/b.conf"), "Host dev\n  Port 2\n").unwrap();
+        fs::write(
+            dir.join("conf.d/a.conf"),
+            "Host dev\n  HostName dev.example.com\n  Port 1\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/.hidden.conf"), "Host dev\n  User hidden\n").unwrap();
+        fs::write(dir.join("other"), "User other\n").unwrap();
+        let source = format!(
+            "Host other\n  Include {0}/other\nHost *\n  Include {0}/conf.d/*.conf\n  User fallback\n",
+            dir.display()
+        );
+        let options = HostOptions::parse(&source, "dev");
+        assert_eq!(options.hostname("dev"), "dev.example.com");
+        assert_eq!(options.get("port").as_deref(), Some("1"));
+        assert_eq!(options.get("user").as_deref(), Some("fallback"));
+        let options = HostOptions::parse(&source, "other");
+        assert_eq!(options.get("user").as_deref(), Some("other"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tokens() {
+        let options = HostOptions::parse(
+            "HostName %h.example.com\nIdentityFile ~/.ssh/%r@%h-%p_%n%%\n",
+            "dev",
+        );
+        let hop = Hop::resolve(&options, "dev", Some(2222), Some(String::from("me")));
+        assert_eq!(hop.hostname, "dev.example.com");
+        assert_eq!(
+            hop.identity_file,
+            Some(expand_path("~/.ssh/me@dev.example.com-2222_dev%", false))
+        );
+        assert_eq!(expand_tokens("%x %", &[]), "%x %");
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(wildcard_match("h?st*", "host-1"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(!wildcard_match("h?st", "hoost"));
+        assert!(host_matches("a b DEV", "dev"));
+    }
+}
@@ -2,6 +2,7 @@ use thiserror::Error;
 
 use crate::remote::Remote;
 use crate::remote::RemoteExecError;
+use crate::shell::ShellCommand;
 
 #[derive(Debug, Error)]
 pub enum RemoteInstallError {
@@ -32,7 +33,7 @@ impl Remote {
     /// First, it's checked if dirsync exists with the same version as the localhost.
     /// If not, dirsync will attempt to download and install it from github.
     pub fn install_dirsync(&mut self) -> Result<(), RemoteInstallError> {
-        let (_, status_code) = self.try_exec("which dirsync")?;
+        let (_, status_code) = self.try_exec(&ShellCommand::new("which").arg("dirsync"))?;
 
         if status_code != 0 {
             self.install_vendored_dirsync()?;
@@ -43,7 +44,13 @@ impl Remote {
 
     /// This function attempts to remove dirsync from the remote host
     pub fn remove_dirsync(&mut self) -> Result<(), RemoteInstallError> {
-        let status_code = self.command(r#"rm -rf .dirsync/client"#)?.stream_to_end()?;
+        let status_code = self
+            .command(
+                &ShellCommand::new("rm")
+                    .arg("-rf")
+                    .path_arg(self.dirsync_client_dir()),
+            )?
+            .stream_to_end()?;
         if status_code != 0 {
             return Err(RemoteInstallError::Error(format!(
                 "Failed to remove dirsync with status: {status_code}"
@@ -57,17 +64,19 @@ impl Remote {
     }
 
     fn install_vendored_dirsync(&mut self) -> Result<(), RemoteInstallError> {
+        let client_dir = self.dirsync_client_dir();
         let status_code = self
             .command(
-                format!(
-                    r#"echo "Making dir: {client_dir}" \
-                && mkdir -p {client_dir} \
-                && cd {client_dir} \
-                && ls \
-                "#,
-                    client_dir = self.dirsync_client_dir()
-                )
-                .as_str(),
+                &ShellCommand::new("echo")
+                    .arg(format!("Making dir: {client_dir}"))
+                    .raw("&&")
+                    .args(["mkdir", "-p"])
+                    .path_arg(client_dir)
+                    .raw("&&")
+                    .arg("cd")
+                    .path_arg(client_dir)
+                    .raw("&&")
+                    .arg("ls"),
             )?
             .stream_to_end()?;
 
@@ -79,15 +88,15 @@ impl Remote {
 
         let status_code = self
             .command(
-                format!(
-                    r#"echo "cloning into dir: {client_dir}"\
-                && cd {client_dir} \
-                && ls \
-                && git clone https://github.com/spencerkohan/dirsync
-                "#,
-                    client_dir = self.dirsync_client_dir()
-                )
-                .as_str(),
+                &ShellCommand::new("echo")
+                    .arg(format!("cloning into dir: {client_dir}"))
+                    .raw("&&")
+                    .arg("cd")
+                    .path_arg(client_dir)
+                    .raw("&&")
+                    .arg("ls")
+                    .raw("&&")
+                    .args(["git", "clone", "https://github.com/spencerkohan/dirsync"]),
             )?
             .stream_to_end()?;
 
@@ -97,14 +106,9 @@ impl Remote {
 
         let status_code = self
             .command(
-                format!(
-                    r#"
-                    cd {client_dir}/dirsync \
-                    && cargo build
-                    "#,
-                    client_dir = self.dirsync_client_dir()
-                )
-                .as_str(),
+                &ShellCommand::new("cargo")
+                    .arg("build")
+                    .current_dir(format!("{client_dir}/dirsync")),
             )?
             .stream_to_end()?;
 
@@ -0,0 +1,167 @@
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ssh2::{BlockDirections, Channel, Session};
+
+/// Open a tunnel to `host:port` through an authenticated jump host session.
+///
+/// ssh2 sessions need a socket, so the `direct-tcpip` channel is bridged to
+/// one end of a unix socket pair by a background thread, and the other end is
+/// returned to be used for the next session.
+/// The thread owns the jump host session, and closes it when the tunnel closes.
+pub fn open_tunnel(
+    session: Session,
+    host: &str,
+    port: u16,
+    keepalive_interval: u32,
+) -> std::io::Result<UnixStream> {
+    let channel = session.channel_direct_tcpip(host, port, None)?;
+    let (local, remote) = UnixStream::pair()?;
+
+    thread::spawn(move || {
+        if let Err(err) = pump(&session, channel, remote, keepalive_interval) {
+            eprintln!("[connection] jump host tunnel closed: {err}");
+        }
+    });
+
+    Ok(local)
+}
+
+/// Copy data in both directions between the channel and the socket,
+/// until either side is closed
+fn pump(
+    session: &Session,
+    mut channel: Channel,
+    mut socket: UnixStream,
+    keepalive_interval: u32,
+) -> std::io::Result<()> {
+    // both sides are handled from this thread, so nothing may block
+    session.set_blocking(false);
+    socket.set_nonblocking(true)?;
+    if keepalive_interval > 0 {
+        session.set_keepalive(true, keepalive_interval);
+    }
+
+    let mut buffer = [0; 16 * 1024];
+    let mut to_channel: Vec<u8> = Vec::new();
+    let mut to_socket: Vec<u8> = Vec::new();
+    let mut next_keepalive = Instant::now();
+
+    loop {
+        let mut progressed = false;
+
+        if to_channel.is_empty() {
+            match socket.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    to_channel.extend_from_slice(&buffer[..n]);
+                    progressed = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !to_channel.is_empty() {
+            match channel.write(&to_channel) {
+                Ok(n) => {
+                    to_channel.drain(..n);
+                    progressed = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if to_socket.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) if channel.eof() => return Ok(()),
+                Ok(0) => {}
+                Ok(n) => {
+                    to_socket.extend_from_slice(&buffer[..n]);
+                    progressed = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !to_socket.is_empty() {
+            match socket.write(&to_socket) {
+                Ok(n) => {
+                    to_socket.drain(..n);
+                    progressed = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        if keepalive_interval > 0 && Instant::now() >= next_keepalive {
+            let seconds = session.keepalive_send().unwrap_or(1);
+            next_keepalive = Instant::now() + Duration::from_secs(seconds.max(1) as u64);
+        }
+
+        if !progressed {
+            let timeout = match keepalive_interval {
+                0 => None,
+                _ => Some(next_keepalive.saturating_duration_since(Instant::now())),
+            };
+            wait(session, &socket, &to_channel, &to_socket, timeout)?;
+        }
+    }
+}
+
+/// Wait until either side of the tunnel can make progress, or the timeout passes
+fn wait(
+    session: &Session,
+    socket: &UnixStream,
+    to_channel: &[u8],
+    to_socket: &[u8],
+    timeout: Option<Duration>,
+) -> std::io::Result<()> {
+    let mut socket_events = 0;
+    if to_channel.is_empty() {
+        socket_events |= libc::POLLIN;
+    }
+    if !to_socket.is_empty() {
+        socket_events |= libc::POLLOUT;
+    }
+    // the session is read for channel data, and for window adjustments when writes are blocked
+    let mut session_events = 0;
+    if to_socket.is_empty() || !to_channel.is_empty() {
+        session_events |= libc::POLLIN;
+    }
+    if matches!(
+        session.block_directions(),
+        BlockDirections::Outbound | BlockDirections::Both
+    ) {
+        session_events |= libc::POLLOUT;
+    }
+
+    let mut fds = [
+        libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: socket_events,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: session.as_raw_fd(),
+            events: session_events,
+            revents: 0,
+        },
+    ];
+    let timeout_ms = timeout.map_or(-1, |timeout| {
+        timeout.as_millis().min(i32::MAX as u128) as i32
+    });
+    // SAFETY: `fds` is a valid array of pollfd structs for the duration of the call
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+    if result < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ssh2::Session;
+
+/// Sends ssh keepalive messages from a background thread,
+/// so that idle connections are not dropped by NAT gateways.
+pub struct Keepalive {
+    stop: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+}
+
+impl Keepalive {
+    pub fn start(session: &Session, interval: u32) -> Keepalive {
+        session.set_keepalive(true, interval);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let session = session.clone();
+        {
+            let stop = Arc::clone(&stop);
+            let failed = Arc::clone(&failed);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match session.keepalive_send() {
+                        Ok(seconds) => thread::sleep(Duration::from_secs(seconds.max(1) as u64)),
+                        Err(err) => {
+                            if !stop.load(Ordering::Relaxed) {
+                                eprintln!("[connection] keepalive failed: {err}");
+                            }
+                            failed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+
+        Keepalive { stop, failed }
+    }
+
+    /// True if sending a keepalive has failed, i.e. the connection is dead
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
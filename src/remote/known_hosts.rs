@@ -0,0 +1,217 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, HashType, KnownHostFileKind, KnownHosts, Session};
+use thiserror::Error;
+
+/// How unknown or changed host keys are handled.
+/// This mirrors OpenSSH's `StrictHostKeyChecking` option.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    /// Only connect to hosts which are already listed in known_hosts
+    Yes,
+    /// Add unknown hosts to known_hosts, but refuse changed keys
+    #[default]
+    AcceptNew,
+    /// Do not check host keys at all
+    No,
+}
+
+#[derive(Debug, Error)]
+pub enum HostKeyError {
+    #[error("The remote host did not provide a host key")]
+    NoHostKey,
+    #[error(
+        "The host key for {host} ({fingerprint}) does not match the key in {}. \
+        Someone could be eavesdropping on the connection, or the host key has changed. \
+        If the change is expected, remove the old key with: ssh-keygen -R {host}",
+        .known_hosts.display()
+    )]
+    Mismatch {
+        host: String,
+        fingerprint: String,
+        known_hosts: PathBuf,
+    },
+    #[error(
+        "The host {host} ({fingerprint}) is not listed in {}, and strict_host_key_checking is set to `yes`",
+        .known_hosts.display()
+    )]
+    Unknown {
+        host: String,
+        fingerprint: String,
+        known_hosts: PathBuf,
+    },
+    #[error("Failed to check the host key against known_hosts: {0}")]
+    KnownHosts(String),
+}
+
+impl From<ssh2::Error> for HostKeyError {
+    fn from(value: ssh2::Error) -> Self {
+        Self::KnownHosts(value.to_string())
+    }
+}
+
+impl From<std::io::Error> for HostKeyError {
+    fn from(value: std::io::Error) -> Self {
+        Self::KnownHosts(value.to_string())
+    }
+}
+
+fn known_hosts_path() -> PathBuf {
+    let home = env::var("HOME").unwrap_or_default();
+    PathBuf::from(home).join(".ssh").join("known_hosts")
+}
+
+/// The name of the host as it is written in known_hosts
+fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{host}]:{port}")
+    }
+}
+
+/// Check the host key of a session which has completed its handshake
+/// against `~/.ssh/known_hosts`, according to the given policy.
+pub fn verify_host_key(
+    session: &Session,
+    host: &str,
+    port: u16,
+    policy: HostKeyPolicy,
+) -> Result<(), HostKeyError> {
+    if policy == HostKeyPolicy::No {
+        return Ok(());
+    }
+
+    let Some((key, key_type)) = session.host_key() else {
+        return Err(HostKeyError::NoHostKey);
+    };
+    let fingerprint = fingerprint(session);
+    let known_hosts_path = known_hosts_path();
+
+    let mut known_hosts = session.known_hosts()?;
+    if known_hosts_path.exists() {
+        read_known_hosts(&mut known_hosts, &known_hosts_path)?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(HostKeyError::Mismatch {
+            host: host.to_string(),
+            fingerprint,
+            known_hosts: known_hosts_path,
+        }),
+        CheckResult::Failure => Err(HostKeyError::KnownHosts(format!(
+            "failed to check the host key for {host}"
+        ))),
+        CheckResult::NotFound => match policy {
+            HostKeyPolicy::AcceptNew => {
+                let name = known_hosts_name(host, port);
+                known_hosts.add(&name, key, "", key_type.into())?;
+                let Some(entry) = known_hosts
+                    .hosts()?
+                    .into_iter()
+                    .find(|entry| entry.name() == Some(name.as_str()))
+                else {
+                    return Err(HostKeyError::KnownHosts(format!(
+                        "failed to add {name} to known_hosts"
+                    )));
+                };
+                let line = known_hosts.write_string(&entry, KnownHostFileKind::OpenSSH)?;
+
+                // append rather than rewrite, so existing entries are left untouched
+                if let Some(dir) = known_hosts_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&known_hosts_path)?;
+                writeln!(file, "{}", line.trim_end())?;
+
+                eprintln!(
+                    "Warning: permanently added {name} ({fingerprint}) to {}",
+                    known_hosts_path.display()
+                );
+                Ok(())
+            }
+            _ => Err(HostKeyError::Unknown {
+                host: host.to_string(),
+                fingerprint,
+                known_hosts: known_hosts_path,
+            }),
+        },
+    }
+}
+
+/// Read known_hosts line by line, skipping the lines libssh2 can't parse
+/// (e.g. `@cert-authority` markers or security key types) with a warning,
+/// rather than failing on the whole file
+fn read_known_hosts(known_hosts: &mut KnownHosts, path: &Path) -> Result<(), HostKeyError> {
+    let source = fs::read_to_string(path)?;
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let skipped = match line.starts_with('@') {
+            true => Some(String::from("markers are not supported")),
+            false => known_hosts
+                .read_str(line, KnownHostFileKind::OpenSSH)
+                .err()
+                .map(|err| err.message().to_string()),
+        };
+        if let Some(reason) = skipped {
+            eprintln!(
+                "Warning: skipping line {} of {}: {reason}",
+                index + 1,
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The SHA256 fingerprint of the host key, formatted like OpenSSH
+fn fingerprint(session: &Session) -> String {
+    match session.host_key_hash(HashType::Sha256) {
+        // OpenSSH fingerprints are unpadded
+        Some(hash) => format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)),
+        None => String::from("unknown fingerprint"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_lines_are_skipped() {
+        let path = env::temp_dir().join(format!("dirsync-known-hosts-{}", std::process::id()));
+        fs::write(
+            &path,
+            "\
+# comment
+@cert-authority *.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
+truncated.example.com
+host.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
+",
+        )
+        .unwrap();
+
+        let session = Session::new().unwrap();
+        let mut known_hosts = session.known_hosts().unwrap();
+        read_known_hosts(&mut known_hosts, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let hosts = known_hosts.hosts().unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name(), Some("host.example.com"));
+    }
+}
@@ -1,49 +1,146 @@
+pub mod auth;
 pub mod cli;
+pub mod connection;
+pub mod host;
 pub mod install_to_remote;
+pub mod jump;
+pub mod keepalive;
+pub mod known_hosts;
 pub mod receive_from_remote;
 pub mod remote_exec;
+pub mod sftp;
+pub mod transfer;
 
 pub use remote_exec::exec_remote;
 
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::io::ErrorKind;
+use std::iter;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
 
 use crate::config::SessionConfig;
+use crate::shell::ShellCommand;
+use crate::transport::CANCEL_POLL_INTERVAL;
+use auth::{authenticate, AuthError};
+use host::Hop;
+use jump::open_tunnel;
+use keepalive::Keepalive;
+use known_hosts::{verify_host_key, HostKeyError};
 use remote_exec::RemoteExecError;
 use ssh2::ExtendedData;
 use ssh2::Session;
+use thiserror::Error;
 
 pub struct Remote {
     session: ssh2::Session,
     root: PathBuf,
+    // timeout for blocking calls, in milliseconds (0 means no timeout)
+    timeout_ms: u32,
+    keepalive_interval: u32,
+    keepalive: Option<Keepalive>,
 }
 
-impl Remote {
-    // todo: this shouold take configuration arguemnts
-    pub fn connect(config: &SessionConfig) -> Remote {
-        let tcp = TcpStream::connect(&config.host_port_string().as_str()).unwrap();
-        let mut sess = Session::new().unwrap();
-        sess.set_tcp_stream(tcp);
-        sess.handshake().unwrap();
+#[derive(Debug, Error)]
+pub enum RemoteConnectError {
+    #[error("Failed to resolve host {0}: {1}")]
+    Dns(String, std::io::Error),
+    #[error("Failed to connect to {0}: {1}")]
+    Tcp(String, std::io::Error),
+    #[error("Failed to open a tunnel to {0} through the jump host: {1}")]
+    Tunnel(String, std::io::Error),
+    #[error("Failed to create ssh session: {0}")]
+    Session(ssh2::Error),
+    #[error("ssh handshake with {0} failed: {1}")]
+    Handshake(String, ssh2::Error),
+    #[error(transparent)]
+    HostKey(#[from] HostKeyError),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("Failed to open {0}: {1}")]
+    Local(String, std::io::Error),
+    #[error("Failed to reach docker container {0}: {1}")]
+    Docker(String, String),
+}
+
+impl RemoteConnectError {
+    /// The exit code the process should use when the connection fails
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RemoteConnectError::Dns(_, _) => 3,
+            RemoteConnectError::Tcp(_, _)
+            | RemoteConnectError::Tunnel(_, _)
+            | RemoteConnectError::Local(_, _)
+            | RemoteConnectError::Docker(_, _) => 4,
+            RemoteConnectError::Session(_) | RemoteConnectError::Handshake(_, _) => 5,
+            RemoteConnectError::HostKey(_) => 6,
+            RemoteConnectError::Auth(_) => 7,
+        }
+    }
+}
 
-        // Try to authenticate with the first identity in the agent.
-        sess.userauth_agent(&config.remote.user.clone().as_str())
-            .unwrap();
+impl Remote {
+    pub fn connect(config: &SessionConfig) -> Result<Remote, RemoteConnectError> {
+        let timeout_ms = config.timeout().map_or(0, |timeout| timeout.as_millis() as u32);
+        let target = config.target_hop();
 
-        // Make sure we succeeded
-        assert!(sess.authenticated());
+        let sess = match config.host.jump.split_first() {
+            None => tcp_session(&target, config)?,
+            Some((first, rest)) => {
+                // tunnel through each jump host in turn
+                let mut sess = tcp_session(first, config)?;
+                for hop in rest.iter().chain(iter::once(&target)) {
+                    let (host, port) = (hop.hostname.as_str(), hop.port);
+                    let stream = open_tunnel(sess, host, port, config.keepalive_interval())
+                        .map_err(|err| RemoteConnectError::Tunnel(format!("{host}:{port}"), err))?;
+                    sess = open_session(stream, hop, config)?;
+                }
+                sess
+            }
+        };
 
         let mut root = PathBuf::new();
-        root.push(&config.remote.root.clone());
+        root.push(config.remote.root.clone());
 
-        return Remote {
+        let keepalive_interval = config.keepalive_interval();
+        let keepalive = match keepalive_interval {
+            0 => None,
+            interval => Some(Keepalive::start(&sess, interval)),
+        };
+
+        Ok(Remote {
             session: sess,
             root,
-        };
+            timeout_ms,
+            keepalive_interval,
+            keepalive,
+        })
+    }
+
+    /// Connect to the remote, or report the error and exit
+    pub fn connect_or_exit(config: &SessionConfig) -> Remote {
+        match Remote::connect(config) {
+            Ok(remote) => remote,
+            Err(err) => {
+                eprintln!("Error connecting to the remote: {err}");
+                exit(err.exit_code());
+            }
+        }
+    }
+
+    /// The command line for a command which runs in the remote root
+    fn in_root(&self, command: &ShellCommand) -> String {
+        ShellCommand::new("cd")
+            .path_arg(self.root.to_string_lossy())
+            .raw("&&")
+            .raw(&command.to_string())
+            .to_string()
     }
 
-    pub fn try_exec(&mut self, command: &str) -> Result<(String, i32), RemoteExecError> {
+    pub fn try_exec(&mut self, command: &ShellCommand) -> Result<(String, i32), RemoteExecError> {
         let com = self.command(command)?;
         match com.result_string() {
             Ok(result) => Ok(result),
@@ -54,66 +151,156 @@ impl Remote {
         }
     }
 
-    fn exec(&mut self, command: &str) -> String {
-        let path = self.root.clone();
-        let path_str = path.to_str().unwrap();
-        let cmd = &format!("cd {} && {}", &path_str, &command);
+    fn exec(&mut self, command: &ShellCommand) -> Result<String, RemoteExecError> {
+        let cmd = &self.in_root(command);
+        let exec_error = |err: &dyn std::fmt::Display| {
+            RemoteExecError::ExecError(cmd.to_string(), err.to_string())
+        };
 
-        let channel = &mut self.session.channel_session().unwrap();
-        channel.exec(&cmd).unwrap();
+        let channel = &mut self.session.channel_session().map_err(|e| exec_error(&e))?;
+        channel.exec(cmd).map_err(|e| exec_error(&e))?;
 
         let mut s = String::new();
-        channel.read_to_string(&mut s).unwrap();
+        channel.read_to_string(&mut s).map_err(|e| exec_error(&e))?;
         println!("exec: {}", &cmd);
         let _ = channel.wait_close();
-        return s;
+        Ok(s)
     }
 
-    fn exec_stream(&mut self, command: &str) {
-        let path = self.root.clone();
-        let path_str = path.to_str().unwrap();
-        let cmd = &format!("cd {} && {}", &path_str, &command);
+    fn exec_stream(
+        &mut self,
+        command: &ShellCommand,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError> {
+        let cmd = &self.in_root(command);
+        let exec_error = |err: &dyn std::fmt::Display| {
+            RemoteExecError::ExecError(cmd.to_string(), err.to_string())
+        };
 
-        let channel = &mut self.session.channel_session().unwrap();
+        let channel = &mut self.session.channel_session().map_err(|e| exec_error(&e))?;
 
         let mut channel_out = channel.stream(0);
 
-        channel.handle_extended_data(ExtendedData::Merge).unwrap();
-        channel.request_pty("term", None, None).unwrap();
-        channel.exec(&cmd).unwrap();
+        channel
+            .handle_extended_data(ExtendedData::Merge)
+            .map_err(|e| exec_error(&e))?;
+        channel
+            .request_pty("term", None, None)
+            .map_err(|e| exec_error(&e))?;
+        channel.exec(cmd).map_err(|e| exec_error(&e))?;
 
-        std::io::copy(&mut channel_out, &mut std::io::stdout()).unwrap();
+        // reads are woken up often, to check whether the command was cancelled
+        self.session
+            .set_timeout(CANCEL_POLL_INTERVAL.as_millis() as u32);
+        let result = copy_stream_until(&mut channel_out, &mut std::io::stdout(), cancelled);
+        self.set_streaming(false);
+        if result.map_err(|e| exec_error(&e))? {
+            // closing the channel hangs up the pty, which stops the command
+            let _ = channel.close();
+            return Err(RemoteExecError::Cancelled(cmd.to_string()));
+        }
         let _ = channel.wait_close();
+        Ok(())
     }
 
-    fn file_exists(&mut self, filename: &str) -> bool {
-        let command = &format!("test -f {} && echo 1 || echo 0", filename);
-        let s = self.exec(&command);
-        return s.as_str() == "1\n";
+    /// Set the timeout for blocking calls while streaming the output of a long-running command.
+    ///
+    /// Reads are woken up at the keepalive interval, so that keepalives can
+    /// still be sent while waiting for output.  Pass `false` to restore the
+    /// configured timeout.
+    pub fn set_streaming(&self, streaming: bool) {
+        set_streaming(
+            &self.session,
+            streaming,
+            self.timeout_ms,
+            self.keepalive_interval,
+        );
     }
+}
 
-    pub fn execute_if_exists(&mut self, event: &str) {
-        let mut path = self.root.clone();
-        path.push(".dirsync/actions");
-        path.push(event);
-        path.push("remote");
-        let path_str = path.to_str().unwrap();
+/// Open an authenticated session over a direct TCP connection
+fn tcp_session(hop: &Hop, config: &SessionConfig) -> Result<Session, RemoteConnectError> {
+    let (host, port) = (hop.hostname.as_str(), hop.port);
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(|err| RemoteConnectError::Dns(host.to_string(), err))?;
+    let tcp = connect_tcp(addrs.collect(), config.timeout())
+        .map_err(|err| RemoteConnectError::Tcp(format!("{host}:{port}"), err))?;
+    open_session(tcp, hop, config)
+}
 
-        if !self.file_exists(&path_str) {
-            println!("file does not exist: {}", &path_str);
-            return;
-        }
+/// Perform the handshake, host key verification and authentication over a stream
+fn open_session<S: 'static + AsRawFd>(
+    stream: S,
+    hop: &Hop,
+    config: &SessionConfig,
+) -> Result<Session, RemoteConnectError> {
+    let (host, port) = (hop.hostname.as_str(), hop.port);
+    let timeout_ms = config.timeout().map_or(0, |timeout| timeout.as_millis() as u32);
+    let mut sess = Session::new().map_err(RemoteConnectError::Session)?;
+    sess.set_tcp_stream(stream);
+    sess.set_timeout(timeout_ms);
+    sess.handshake()
+        .map_err(|err| RemoteConnectError::Handshake(format!("{host}:{port}"), err))?;
+
+    let policy = config.remote.strict_host_key_checking.unwrap_or_default();
+    verify_host_key(&sess, host, port, policy)?;
+    authenticate(&sess, hop, config)?;
+    Ok(sess)
+}
 
-        let command1 = &format!("chmod +x {}", &path_str);
-        let _ = self.exec(&command1);
+fn connect_tcp(
+    addrs: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+) -> Result<TcpStream, std::io::Error> {
+    let mut last_error = std::io::Error::new(ErrorKind::NotFound, "no addresses found");
+    for addr in addrs {
+        let result = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match result {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
 
-        let command2 = &format!("{}", &path_str);
-        self.exec_stream(&command2);
+fn set_streaming(session: &Session, streaming: bool, timeout_ms: u32, keepalive_interval: u32) {
+    if streaming {
+        session.set_timeout(keepalive_interval.saturating_mul(1000));
+    } else {
+        session.set_timeout(timeout_ms);
     }
+}
+
+/// Copy a stream until EOF, ignoring the timeouts used to wake up for keepalives
+pub fn copy_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> std::io::Result<()> {
+    copy_stream_until(reader, writer, &|| false).map(|_| ())
+}
 
-    pub fn remove_dir(&mut self, path: &str) {
-        let command = &format!("rm -rf {}", path);
-        let s = self.exec(&command);
-        print!("clean result: {}\n", s);
+/// Copy a stream until EOF, or until `cancelled` returns true when a read times out.
+/// Returns true if it was cancelled.
+fn copy_stream_until<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    cancelled: &dyn Fn() -> bool,
+) -> std::io::Result<bool> {
+    let mut buffer = [0; 1024];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(false),
+            Ok(n) => {
+                writer.write_all(&buffer[..n])?;
+                writer.flush()?;
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                if cancelled() {
+                    return Ok(true);
+                }
+            }
+            Err(err) => return Err(err),
+        }
     }
 }
@@ -1,58 +1,323 @@
 use std::{
-    io::{BufRead, BufReader},
+    collections::BTreeSet,
+    io::{BufRead, BufReader, ErrorKind},
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
-use crate::config::SessionConfig;
+use crate::config::{SessionConfig, USAGE_ERROR};
+use crate::echo::EchoGuard;
+use crate::shell::ShellCommand;
+use crate::sync::ChangeSet;
+use crate::transport;
+use crate::watch::FileEvent;
 
 use super::Remote;
 
+// how long to wait for more remote events before pulling
+const PULL_DEBOUNCE: Duration = Duration::from_millis(100);
+
+// the delays between attempts to restart the remote watch
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The local path for a path reported by the remote watcher,
+/// if it is safely inside the root
+fn local_path(path: &str) -> Option<PathBuf> {
+    let path: PathBuf = Path::new(path)
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(path)
+}
+
+/// Make a path reported by the remote watcher relative to the root.
+/// Clients installed from upstream report absolute paths, so the absolute
+/// forms of the root are stripped from them.
+pub fn strip_root(roots: &[PathBuf], path: &str) -> String {
+    roots
+        .iter()
+        .find_map(|root| Path::new(path).strip_prefix(root).ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or(path.to_string())
+}
+
+/// The local path for a path reported by the remote watcher,
+/// if it is safely inside one of the receive paths
+fn receivable_path(config: &SessionConfig, path: &str) -> Option<PathBuf> {
+    local_path(path).filter(|path| config.is_in_receive_path(path))
+}
+
+/// Pull changed paths from the remote, once each burst of events has settled
+fn start_pull_thread(config: SessionConfig, paths_rx: Receiver<PathBuf>) {
+    let mut transport = transport::connect_or_exit(&config);
+    thread::spawn(move || loop {
+        let Ok(path) = paths_rx.recv() else {
+            return;
+        };
+        let mut paths = BTreeSet::from([path]);
+        while let Ok(path) = paths_rx.recv_timeout(PULL_DEBOUNCE) {
+            paths.insert(path);
+        }
+
+        println!("Pulling {} paths from the remote", paths.len());
+        if let Err(err) = transport.pull(&config, &paths) {
+            eprintln!("Failed to pull from the remote: {err}");
+            if !transport.is_alive() {
+                match transport::connect(&config) {
+                    Ok(reconnected) => transport = reconnected,
+                    Err(err) => eprintln!("Failed to reconnect to the remote: {err}"),
+                }
+            }
+        }
+    });
+}
+
+/// Watch the receive paths on the remote, and pull any changes into the local tree.
+/// The local watcher skips the receive paths in push mode, so the pulls are never pushed back.
 pub fn watch_remote_receivable_paths(config: SessionConfig) {
     let Some(paths) = &config.remote.receive_paths else {
         return;
     };
-    let root = config.remote.root.clone();
-
-    let mut remote = Remote::connect(&config);
     let paths = paths
-        .into_iter()
+        .iter()
         .map(|path| path.path.clone())
-        .collect::<Vec<String>>()
-        .join(" ");
+        .collect::<Vec<String>>();
+
+    let (paths_tx, paths_rx) = channel();
+    start_pull_thread(config.clone(), paths_rx);
+
+    let filter_config = config.clone();
+    let restart_tx = paths_tx.clone();
+    let receive_paths = paths.clone();
+    start_remote_watch(
+        &config,
+        &paths,
+        move |path| {
+            if let Some(path) = receivable_path(&filter_config, path) {
+                let _ = paths_tx.send(path);
+            }
+        },
+        // pull the receive paths whole, including the deletions
+        move || {
+            for path in &receive_paths {
+                let _ = restart_tx.send(PathBuf::from(path));
+            }
+        },
+    );
+}
+
+/// Watch the whole remote root, and add changed paths to the change set.
+/// Used in bidirectional mode, where the reconciler decides what to do with them.
+/// Writes made by the reconciler's own pushes are echoes, and skipped.
+pub fn watch_remote_root(
+    config: SessionConfig,
+    changes: Arc<Mutex<ChangeSet>>,
+    flush_signal: Sender<()>,
+    echo: EchoGuard,
+) {
+    let restart_changes = Arc::clone(&changes);
+    let restart_signal = flush_signal.clone();
+    start_remote_watch(
+        &config,
+        &[String::from(".")],
+        move |path| {
+            let Some(path) = local_path(path) else {
+                return;
+            };
+            if path.starts_with(".dirsync") || echo.is_echo(&path) {
+                return;
+            }
+            changes.lock().unwrap().insert(path);
+            let _ = flush_signal.send(());
+        },
+        // reconcile the whole tree
+        move || {
+            restart_changes.lock().unwrap().set_full();
+            let _ = restart_signal.send(());
+        },
+    );
+}
 
-    // spawn a thread to connect to the other rsync instance
+/// Watch the given paths (relative to the remote root) on the target in the background,
+/// and call `on_path` with each path it reports.
+/// The watch is restarted with backoff when it stops, e.g. because the connection was lost,
+/// and `on_restart` is called before each restart to catch up on the changes missed meanwhile.
+fn start_remote_watch(
+    config: &SessionConfig,
+    paths: &[String],
+    on_path: impl Fn(&str) + Send + 'static,
+    on_restart: impl Fn() + Send + 'static,
+) {
+    let mut transport = transport::connect_or_exit(config);
+    let config = config.clone();
+    let paths = paths.to_vec();
     thread::spawn(move || {
-        if let Err(err) = remote.install_dirsync() {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            transport.watch(&config, &paths, &on_path);
+            // a watch which ran for a while was stopped by a lost connection,
+            // rather than failing to start
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = INITIAL_BACKOFF;
+            }
+            eprintln!(
+                "The remote watch stopped, restarting it in {}s",
+                backoff.as_secs()
+            );
+            transport = loop {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                match transport::connect(&config) {
+                    Ok(transport) => break transport,
+                    Err(err) => eprintln!(
+                        "Failed to reconnect the remote watch: {err}, retrying in {}s",
+                        backoff.as_secs()
+                    ),
+                }
+            };
+            on_restart();
+        }
+    });
+}
+
+impl Remote {
+    /// The absolute path of the remote root, both as the shell reports it
+    /// and with symlinks resolved
+    fn absolute_roots(&mut self) -> Vec<PathBuf> {
+        match self.exec(&ShellCommand::new("pwd").raw("&&").args(["pwd", "-P"])) {
+            Ok(output) => output.lines().map(PathBuf::from).collect(),
+            Err(err) => {
+                eprintln!("Failed to resolve the remote root: {err}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Run `dirsync watch` on the remote, until it stops or the connection is lost
+    pub fn watch_remote(
+        mut self,
+        config: &SessionConfig,
+        paths: &[String],
+        on_path: &dyn Fn(&str),
+    ) {
+        let root = &config.remote.root;
+        if let Err(err) = self.install_dirsync() {
             eprintln!("Error installing dirsync at the remote: ${err}");
         }
+        let roots = self.absolute_roots();
 
         // launch cargo in the other directory
-        let Ok(mut command) = remote.command(&format!(
-            r#"cargo run --manifest-path {root}/{dirsync_client_dir}/dirsync/Cargo.toml \
-            -- watch -r {root} {paths}
-            "#,
-            dirsync_client_dir = remote.dirsync_client_dir()
-        )) else {
+        // the command already runs in the remote root, so the watch root is relative to it
+        let manifest = format!("{root}/{}/dirsync/Cargo.toml", self.dirsync_client_dir());
+        let client = ShellCommand::new("cargo")
+            .args(["run", "--manifest-path"])
+            .path_arg(manifest)
+            .args(["--", "watch", "-r", "."]);
+        // an older client rejects the watcher options, so it is run again without them
+        if self.run_watch(&client, &config.watch_args(), paths, &roots, on_path) {
+            eprintln!("Warning: the remote dirsync client can't poll, using its native watcher");
+            self.run_watch(&client, &[], paths, &roots, on_path);
+        }
+    }
+
+    /// Run the `dirsync watch` client, and call `on_path` with each changed path.
+    /// Returns true if the client rejected the watcher options.
+    fn run_watch(
+        &mut self,
+        client: &ShellCommand,
+        watch_args: &[String],
+        paths: &[String],
+        roots: &[PathBuf],
+        on_path: &dyn Fn(&str),
+    ) -> bool {
+        let Ok(mut command) = self.command(&client.clone().args(watch_args).args(paths)) else {
             eprintln!("Failed to init cargo command");
-            return;
+            return false;
         };
 
         if let Err(err) = command.exec() {
             eprintln!("Error executing remote: ${err}");
         }
 
+        // wake up periodically, so that keepalives are sent while waiting for events
+        command.set_streaming(true);
         let stdout = command.channel.stream(0);
-        let stdout_reader = BufReader::new(stdout);
+        let mut stdout_reader = BufReader::new(stdout);
 
-        for line in stdout_reader.lines() {
-            match line {
-                Ok(line) => println!("Read line from remote: {}", line),
-                Err(e) => eprintln!("Error reading line: {}", e),
+        let mut changed = false;
+        let mut line = String::new();
+        loop {
+            match stdout_reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    match serde_json::from_str::<FileEvent>(line.trim()) {
+                        Ok(event) => {
+                            changed = true;
+                            for path in event.paths() {
+                                on_path(&strip_root(roots, path));
+                            }
+                        }
+                        // other output from the remote, e.g. from building the client
+                        Err(_) => println!("Read line from remote: {}", line.trim_end()),
+                    }
+                    line.clear();
+                }
+                // a dropped connection doesn't always end the stream, but the keepalives fail
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    if self.keepalive.as_ref().is_some_and(|k| k.has_failed()) {
+                        eprintln!("Lost the connection to the remote watcher");
+                        return false;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading line: {}", e);
+                    break;
+                }
             }
         }
 
-        if let Err(err) = command.wait_close() {
-            eprintln!("Error finishing command: ${err}");
+        match command.wait_close() {
+            Ok(status) => !changed && !watch_args.is_empty() && status == USAGE_ERROR,
+            Err(err) => {
+                eprintln!("Error finishing command: ${err}");
+                false
+            }
         }
-    });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_paths_are_made_relative() {
+        let roots = [
+            PathBuf::from("/home/me/project"),
+            PathBuf::from("/data/project"),
+        ];
+        let local = |path| local_path(&strip_root(&roots, path));
+        assert_eq!(
+            local("/home/me/project/out/a.txt"),
+            Some(PathBuf::from("out/a.txt"))
+        );
+        assert_eq!(local("/data/project/out"), Some(PathBuf::from("out")));
+        assert_eq!(local("./out/a.txt"), Some(PathBuf::from("out/a.txt")));
+        assert_eq!(local("out/a.txt"), Some(PathBuf::from("out/a.txt")));
+        assert_eq!(local("/home/me/project"), None);
+        assert_eq!(local("/elsewhere/a.txt"), None);
+        assert_eq!(local("out/../../a.txt"), None);
+    }
 }
@@ -1,41 +1,46 @@
 use std::{
-    collections::HashMap,
-    io::{BufReader, Read, Write},
+    io::{BufReader, Read},
     thread,
 };
 
-use ssh2::Channel;
+use ssh2::{Channel, Session};
 use thiserror::Error;
 
 use crate::config::SessionConfig;
+use crate::shell::ShellCommand;
 
-use super::Remote;
+use super::{copy_stream, set_streaming, Remote, RemoteConnectError};
 
 pub fn exec_remote(
     config: &SessionConfig,
-    command: &str,
+    command: &ShellCommand,
 ) -> Result<(String, i32), RemoteExecError> {
-    let mut remote = Remote::connect(config);
+    let mut remote = Remote::connect(config)?;
     remote.try_exec(command)
 }
 
 pub struct RemoteCommand {
     command: String,
     pub channel: Channel,
-    pub environment: HashMap<String, String>,
+    session: Session,
+    timeout_ms: u32,
+    keepalive_interval: u32,
 }
 
 #[derive(Debug, Error)]
 pub enum RemoteExecError {
     #[error("Failed to execute remote command: {0} with eror: {1}")]
     ExecError(String, String),
+    #[error(transparent)]
+    Connect(#[from] RemoteConnectError),
+    #[error("Cancelled remote command: {0}")]
+    Cancelled(String),
 }
 
 impl Remote {
-    pub fn command(&self, command: &str) -> Result<RemoteCommand, RemoteExecError> {
-        let path = self.root.clone();
-        let path_str = path.to_str().unwrap();
-        let cmd = &format!(r#"cd {} && {}"#, &path_str, &command);
+    /// A command which runs in the remote root
+    pub fn command(&self, command: &ShellCommand) -> Result<RemoteCommand, RemoteExecError> {
+        let cmd = &self.in_root(command);
 
         let channel = match self.session.channel_session() {
             Ok(channel) => channel,
@@ -45,26 +50,16 @@ impl Remote {
         Ok(RemoteCommand {
             command: cmd.to_string(),
             channel,
-            environment: Default::default(),
+            session: self.session.clone(),
+            timeout_ms: self.timeout_ms,
+            keepalive_interval: self.keepalive_interval,
         })
     }
 }
 
 impl RemoteCommand {
-    pub fn env(mut self, var: &str, val: &str) -> Result<Self, ssh2::Error> {
-        self.environment.insert(var.to_string(), val.to_string());
-        Ok(self)
-    }
-
     pub fn exec(&mut self) -> Result<(), RemoteExecError> {
-        let environ: String = self
-            .environment
-            .iter()
-            .map(|(key, val)| format!("{key}={val}"))
-            .collect::<Vec<String>>()
-            .join(" ");
-
-        let cmd = format!("{environ} {}", self.command);
+        let cmd = &self.command;
 
         println!("Executing command: \n\n{cmd}\n\n");
 
@@ -75,7 +70,7 @@ impl RemoteCommand {
             ));
         };
 
-        if let Err(err) = self.channel.exec(&cmd) {
+        if let Err(err) = self.channel.exec(cmd) {
             return Err(RemoteExecError::ExecError(
                 self.command.to_string(),
                 err.to_string(),
@@ -84,6 +79,16 @@ impl RemoteCommand {
         Ok(())
     }
 
+    /// See `Remote::set_streaming`
+    pub fn set_streaming(&self, streaming: bool) {
+        set_streaming(
+            &self.session,
+            streaming,
+            self.timeout_ms,
+            self.keepalive_interval,
+        );
+    }
+
     pub fn wait_close(&mut self) -> Result<i32, std::io::Error> {
         self.channel.send_eof()?;
         self.channel.wait_close()?;
@@ -118,7 +123,10 @@ impl RemoteCommand {
         println!("stream_to_end: exec({})", self.command);
         self.exec()?;
 
-        if let Err(err) = self.stream_impl() {
+        self.set_streaming(true);
+        let result = self.stream_impl();
+        self.set_streaming(false);
+        if let Err(err) = result {
             return Err(RemoteExecError::ExecError(self.command, err.to_string()));
         };
 
@@ -141,31 +149,8 @@ impl RemoteCommand {
         let mut stderr_reader = BufReader::new(self.channel.stderr());
 
         // Spawn threads to read stdout and stderr concurrently
-        let stdout_handle = thread::spawn(move || -> std::io::Result<()> {
-            loop {
-                let mut buffer = [0; 1024];
-                match stdout_reader.read(&mut buffer) {
-                    Ok(0) => break, // EOF reached
-                    Ok(n) => stdout.write_all(&buffer[..n])?,
-                    Err(e) => return Err(e),
-                }
-                stdout.flush()?;
-            }
-            Ok(())
-        });
-
-        let stderr_handle = thread::spawn(move || -> std::io::Result<()> {
-            loop {
-                let mut buffer = [0; 1024];
-                match stderr_reader.read(&mut buffer) {
-                    Ok(0) => break, // EOF reached
-                    Ok(n) => stderr.write_all(&buffer[..n])?,
-                    Err(e) => return Err(e),
-                }
-                stderr.flush()?;
-            }
-            Ok(())
-        });
+        let stdout_handle = thread::spawn(move || copy_stream(&mut stdout_reader, &mut stdout));
+        let stderr_handle = thread::spawn(move || copy_stream(&mut stderr_reader, &mut stderr));
 
         // Wait for both threads to finish
         stdout_handle.join().unwrap()?;
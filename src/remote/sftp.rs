@@ -0,0 +1,582 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use ssh2::{FileStat, OpenFlags, OpenType, Session, Sftp};
+
+use crate::config::SessionConfig;
+use crate::delta::{diff, is_in_place, literal_size, Op, Signature};
+use crate::ignore::IgnoreRules;
+use crate::plan::{Plan, PlannedChange};
+use crate::shell::ShellCommand;
+use crate::state::{sha256_file, FileVersion, SyncState};
+use crate::sync::deletes_enabled;
+use crate::transfer_set::{pull_rules, TransferSet};
+
+use super::Remote;
+
+// large buffers keep the number of sftp round trips down
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Transfers files to and from the remote root over sftp,
+/// using the session which is already open for the remote.
+pub struct SftpTransfer<'a> {
+    sftp: Sftp,
+    session: Session,
+    config: &'a SessionConfig,
+    local_root: PathBuf,
+    /// The remote root, as a path understood by the sftp server
+    root: PathBuf,
+    ignore: IgnoreRules,
+    // remote directories which are known to exist
+    created_dirs: HashSet<PathBuf>,
+    /// The changes found in a dry run, which are not made
+    plan: Option<Plan>,
+}
+
+/// Add the path to an io error
+fn context(path: &Path) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |err| io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_secs())
+}
+
+/// A temporary file next to `path`, which is renamed over it once complete
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.dirsync-tmp"))
+}
+
+impl Remote {
+    pub fn sftp_transfer<'a>(&self, config: &'a SessionConfig) -> io::Result<SftpTransfer<'a>> {
+        // sftp resolves relative paths against the home directory, like the shell
+        let root = match self.root.strip_prefix("~") {
+            Ok(root) if root.as_os_str().is_empty() => PathBuf::from("."),
+            Ok(root) => root.to_path_buf(),
+            Err(_) => self.root.clone(),
+        };
+        Ok(SftpTransfer {
+            sftp: self.session.sftp()?,
+            session: self.session.clone(),
+            config,
+            local_root: PathBuf::from(&config.local_root),
+            root,
+            ignore: IgnoreRules::new(config),
+            created_dirs: HashSet::new(),
+            plan: config.dry_run.then(Plan::default),
+        })
+    }
+}
+
+impl SftpTransfer<'_> {
+    /// The changes found in a dry run, or an empty plan if the transfers ran
+    pub fn into_plan(self) -> Plan {
+        self.plan.unwrap_or_default()
+    }
+
+    /// Record a change instead of making it, if this is a dry run
+    fn planned(&mut self, path: &Path, change: PlannedChange) -> bool {
+        match &mut self.plan {
+            Some(plan) => {
+                plan.insert(path.to_path_buf(), change);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Push the .dirsync directory, which is never ignored
+    pub fn push_dirsync_dir(&mut self, state: &SyncState) -> io::Result<()> {
+        let set = TransferSet::dirsync_dir(self.config);
+        self.push_set(&set, state)
+    }
+
+    /// Push the whole tree, and delete remote files which don't exist locally if enabled
+    pub fn push_all(&mut self, state: &SyncState) -> io::Result<()> {
+        self.push_dirsync_dir(state)?;
+
+        let mut remote = BTreeMap::new();
+        if deletes_enabled(self.config) {
+            self.collect_remote(Path::new(""), Some(&self.ignore), &mut remote)?;
+        }
+        let set = TransferSet::push_all(self.config, &self.ignore, &remote);
+        self.push_set(&set, state)
+    }
+
+    /// Push the given paths.
+    /// Paths which no longer exist locally are deleted on the remote, if enabled.
+    pub fn push(&mut self, paths: &BTreeSet<PathBuf>, state: &SyncState) -> io::Result<()> {
+        for path in paths {
+            println!("  {}", path.display());
+        }
+        let set = TransferSet::push(self.config, &self.ignore, paths);
+        self.push_set(&set, state)
+    }
+
+    fn push_set(&mut self, set: &TransferSet, state: &SyncState) -> io::Result<()> {
+        for (path, is_dir) in &set.copied {
+            let local_path = self.local_root.join(path);
+            if *is_dir {
+                self.create_dir(path)?;
+            } else if local_path
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                self.upload(path, state.get(path))?;
+            } else {
+                println!(
+                    "skipping {}: only regular files and directories are synced over sftp",
+                    path.display()
+                );
+            }
+        }
+        for path in &set.deleted {
+            println!("deleting {}", path.display());
+            self.remove(path)?;
+        }
+        Ok(())
+    }
+
+    /// Pull the given paths from the remote.
+    /// Paths which no longer exist on the remote are deleted locally.
+    pub fn pull(&mut self, paths: &BTreeSet<PathBuf>) -> io::Result<()> {
+        let ignore = pull_rules(self.config);
+        let mut remote = BTreeMap::new();
+        for path in paths {
+            println!("  {}", path.display());
+            let remote_path = self.root.join(path);
+            match self.sftp.lstat(&remote_path).map_err(io::Error::from) {
+                Ok(stat) if stat.is_dir() => {
+                    remote.insert(path.clone(), true);
+                    self.collect_remote(path, ignore.as_ref(), &mut remote)?;
+                }
+                Ok(_) => {
+                    remote.insert(path.clone(), false);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(context(&remote_path)(err)),
+            }
+        }
+
+        let set = TransferSet::pull(self.config, paths, &remote);
+        for path in set.copied.keys() {
+            let stat = self.sftp.lstat(&self.root.join(path))?;
+            self.download(path, &stat)?;
+        }
+        for path in &set.deleted {
+            let local_path = self.local_root.join(path);
+            if local_path.symlink_metadata().is_ok() && self.planned(path, PlannedChange::Delete) {
+                continue;
+            }
+            remove_local(&local_path)?;
+        }
+        Ok(())
+    }
+
+    /// Add the remote paths below `path` and whether they are directories,
+    /// leaving out ignored directories and the .dirsync directory
+    fn collect_remote(
+        &self,
+        path: &Path,
+        ignore: Option<&IgnoreRules>,
+        entries: &mut BTreeMap<PathBuf, bool>,
+    ) -> io::Result<()> {
+        let remote_path = self.root.join(path);
+        let listing = self
+            .sftp
+            .readdir(&remote_path)
+            .map_err(|err| context(&remote_path)(err.into()))?;
+        for (child_path, stat) in listing {
+            let Some(name) = child_path.file_name() else {
+                continue;
+            };
+            let child = path.join(name);
+            if child.starts_with(".dirsync") && !path.starts_with(".dirsync") {
+                continue;
+            }
+            entries.insert(child.clone(), stat.is_dir());
+            if stat.is_dir() && !ignore.is_some_and(|ignore| ignore.is_ignored(&child, true)) {
+                self.collect_remote(&child, ignore, entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a remote directory and its parents, replacing files in the way
+    fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        if path.as_os_str().is_empty() || self.created_dirs.contains(path) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir(parent)?;
+        }
+        let remote_path = self.root.join(path);
+        match self.sftp.lstat(&remote_path) {
+            Ok(stat) if stat.is_dir() => {}
+            Ok(_) if self.planned(path, PlannedChange::Update) => {}
+            Err(_) if self.planned(path, PlannedChange::Create) => {}
+            Ok(_) => {
+                self.sftp.unlink(&remote_path)?;
+                self.sftp.mkdir(&remote_path, 0o755)?;
+            }
+            Err(_) => self
+                .sftp
+                .mkdir(&remote_path, 0o755)
+                .map_err(|err| context(&remote_path)(err.into()))?,
+        }
+        self.created_dirs.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Upload a file, unless the remote already has the same size and modification time.
+    /// If the remote still has the last synced version of a large file,
+    /// only the changed blocks are transferred.
+    fn upload(&mut self, path: &Path, synced: Option<&FileVersion>) -> io::Result<()> {
+        let local_path = self.local_root.join(path);
+        let remote_path = self.root.join(path);
+        let metadata = fs::metadata(&local_path).map_err(context(&local_path))?;
+        let size = metadata.len();
+        let mtime = mtime_secs(&metadata);
+
+        let remote_stat = self.sftp.lstat(&remote_path).ok();
+        if let Some(stat) = &remote_stat {
+            if stat.is_file() && stat.size == Some(size) && stat.mtime == Some(mtime) {
+                return Ok(());
+            }
+        }
+        let change = match &remote_stat {
+            Some(_) => PlannedChange::Update,
+            None => PlannedChange::Create,
+        };
+        if self.planned(path, change) {
+            return Ok(());
+        }
+        if remote_stat.as_ref().is_some_and(|stat| stat.is_dir()) {
+            self.remove(path)?;
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir(parent)?;
+        }
+
+        // the remote file must still be exactly the version the signature was made from
+        let delta_base = match (&remote_stat, synced) {
+            (Some(stat), Some(synced))
+                if stat.is_file()
+                    && stat.size == Some(synced.size)
+                    && stat.mtime == Some(synced.mtime) =>
+            {
+                synced
+                    .signature
+                    .as_ref()
+                    .map(|signature| (signature, synced.size))
+            }
+            _ => None,
+        };
+        let sent_delta = match delta_base {
+            Some((signature, old_size)) => self.upload_delta(path, signature, old_size, size)?,
+            None => false,
+        };
+        if !sent_delta {
+            self.upload_whole(path)?;
+        }
+
+        let mode = metadata.permissions().mode() & 0o7777;
+        self.sftp
+            .setstat(
+                &remote_path,
+                FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: Some(mode),
+                    atime: Some(mtime),
+                    mtime: Some(mtime),
+                },
+            )
+            .map_err(|err| context(&remote_path)(err.into()))
+    }
+
+    fn upload_whole(&mut self, path: &Path) -> io::Result<()> {
+        let local_path = self.local_root.join(path);
+        let remote_path = self.root.join(path);
+        let temp = temp_path(&remote_path);
+
+        let mut local_file = File::open(&local_path).map_err(context(&local_path))?;
+        let mut remote_file = self
+            .sftp
+            .open_mode(
+                &temp,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o644,
+                OpenType::File,
+            )
+            .map_err(|err| context(&temp)(err.into()))?;
+        copy_range(&mut local_file, &mut remote_file, u64::MAX)?;
+        drop(remote_file);
+        self.replace(&temp, &remote_path)
+    }
+
+    /// Transfer only the parts of a file which differ from the remote version.
+    /// The new version is built in a temporary file, and only replaces the remote
+    /// file if its SHA-256 matches the local file.
+    /// Returns false if the file should be transferred whole instead.
+    fn upload_delta(
+        &mut self,
+        path: &Path,
+        signature: &Signature,
+        old_size: u64,
+        new_size: u64,
+    ) -> io::Result<bool> {
+        let local_path = self.local_root.join(path);
+        let remote_path = self.root.join(path);
+        let ops = diff(signature, &local_path).map_err(context(&local_path))?;
+        let literal = literal_size(&ops);
+        if literal * 2 > new_size {
+            return Ok(false);
+        }
+
+        let temp = temp_path(&remote_path);
+        let mut local_file = File::open(&local_path).map_err(context(&local_path))?;
+        if is_in_place(&ops, signature.block_size) {
+            // unchanged blocks stay where they are, so the old version is copied on the
+            // remote, and only the changed ranges are written into the copy
+            let copied = self.run(
+                &ShellCommand::new("cp")
+                    .args(["-p", "--"])
+                    .arg(remote_path.to_string_lossy())
+                    .arg(temp.to_string_lossy()),
+            );
+            if let Err(err) = copied {
+                eprintln!("Warning: {err}, sending {} whole", path.display());
+                return Ok(false);
+            }
+            let mut temp_file = self
+                .sftp
+                .open_mode(&temp, OpenFlags::WRITE, 0o644, OpenType::File)
+                .map_err(|err| context(&temp)(err.into()))?;
+            for op in &ops {
+                if let Op::Literal { offset, len } = op {
+                    local_file.seek(SeekFrom::Start(*offset))?;
+                    temp_file.seek(SeekFrom::Start(*offset))?;
+                    copy_range(&mut local_file, &mut temp_file, *len)?;
+                }
+            }
+            temp_file.setstat(FileStat {
+                size: Some(new_size),
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: None,
+                mtime: None,
+            })?;
+        } else {
+            // moved blocks are copied from the old version on the remote into a new file
+            let mut old_file = self
+                .sftp
+                .open(&remote_path)
+                .map_err(|err| context(&remote_path)(err.into()))?;
+            let mut temp_file = self
+                .sftp
+                .open_mode(
+                    &temp,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    0o644,
+                    OpenType::File,
+                )
+                .map_err(|err| context(&temp)(err.into()))?;
+            let block_size = signature.block_size as u64;
+            for op in &ops {
+                match op {
+                    Op::Copy { block } => {
+                        let offset = *block as u64 * block_size;
+                        old_file.seek(SeekFrom::Start(offset))?;
+                        copy_range(
+                            &mut old_file,
+                            &mut temp_file,
+                            block_size.min(old_size - offset),
+                        )?;
+                    }
+                    Op::Literal { offset, len } => {
+                        local_file.seek(SeekFrom::Start(*offset))?;
+                        copy_range(&mut local_file, &mut temp_file, *len)?;
+                    }
+                }
+            }
+        }
+
+        // blocks are matched by a weak and a 64-bit hash, so the result is checked as a whole
+        let expected = sha256_file(&local_path).map_err(context(&local_path))?;
+        let actual = self.run(
+            &ShellCommand::new("sha256sum")
+                .arg("--")
+                .arg(temp.to_string_lossy()),
+        );
+        match actual {
+            Ok(output) if output.split_whitespace().next() == Some(expected.as_str()) => {}
+            result => {
+                if let Err(err) = result {
+                    eprintln!("Warning: {err}");
+                }
+                eprintln!(
+                    "Warning: the delta transfer of {} doesn't match, sending it whole",
+                    path.display()
+                );
+                let _ = self.sftp.unlink(&temp);
+                return Ok(false);
+            }
+        }
+        self.replace(&temp, &remote_path)?;
+
+        println!(
+            "delta transfer of {}: sent {literal} of {new_size} bytes",
+            path.display()
+        );
+        Ok(true)
+    }
+
+    /// Run a command on the remote.
+    /// It starts in the home directory, where sftp resolves relative paths as well.
+    fn run(&self, command: &ShellCommand) -> io::Result<String> {
+        let command = command.to_string();
+        let mut channel = self.session.channel_session()?;
+        channel.exec(&command)?;
+        let mut output = String::new();
+        channel.read_to_string(&mut output)?;
+        channel.wait_close()?;
+        match channel.exit_status()? {
+            0 => Ok(output),
+            status => Err(io::Error::other(format!(
+                "`{command}` failed with status {status}"
+            ))),
+        }
+    }
+
+    /// Rename a remote file over another one
+    fn replace(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.sftp.rename(from, to, None).is_ok() {
+            return Ok(());
+        }
+        // sftp servers may refuse to rename over an existing file
+        let _ = self.sftp.unlink(to);
+        self.sftp
+            .rename(from, to, None)
+            .map_err(|err| context(to)(err.into()))
+    }
+
+    /// Download a remote file or create a directory, unless the local one
+    /// already has the same size and modification time
+    fn download(&mut self, path: &Path, stat: &FileStat) -> io::Result<()> {
+        let local_path = self.local_root.join(path);
+        let local = local_path.symlink_metadata().ok();
+        if stat.is_dir() {
+            match &local {
+                Some(local) if local.is_dir() => return Ok(()),
+                Some(_) if self.planned(path, PlannedChange::Update) => return Ok(()),
+                None if self.planned(path, PlannedChange::Create) => return Ok(()),
+                _ => {}
+            }
+            if local.as_ref().is_some_and(|local| !local.is_dir()) {
+                remove_local(&local_path)?;
+            }
+            return fs::create_dir_all(&local_path).map_err(context(&local_path));
+        }
+        if !stat.is_file() {
+            return Ok(());
+        }
+        if let Some(local) = &local {
+            if local.is_file()
+                && Some(local.len()) == stat.size
+                && Some(mtime_secs(local)) == stat.mtime
+            {
+                return Ok(());
+            }
+        }
+        let change = match &local {
+            Some(_) => PlannedChange::Update,
+            None => PlannedChange::Create,
+        };
+        if self.planned(path, change) {
+            return Ok(());
+        }
+        if local.as_ref().is_some_and(|local| local.is_dir()) {
+            remove_local(&local_path)?;
+        }
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).map_err(context(parent))?;
+        }
+
+        let remote_path = self.root.join(path);
+        let temp = temp_path(&local_path);
+        let mut remote_file = self
+            .sftp
+            .open(&remote_path)
+            .map_err(|err| context(&remote_path)(err.into()))?;
+        let mut local_file = File::create(&temp).map_err(context(&temp))?;
+        copy_range(&mut remote_file, &mut local_file, u64::MAX)?;
+        if let Some(mtime) = stat.mtime {
+            local_file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        if let Some(perm) = stat.perm {
+            local_file.set_permissions(fs::Permissions::from_mode(perm & 0o7777))?;
+        }
+        drop(local_file);
+        fs::rename(&temp, &local_path).map_err(context(&local_path))
+    }
+
+    /// Remove a remote file or directory, recursively
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let remote_path = self.root.join(path);
+        let Ok(stat) = self.sftp.lstat(&remote_path) else {
+            return Ok(());
+        };
+        if self.planned(path, PlannedChange::Delete) {
+            return Ok(());
+        }
+        if stat.is_dir() {
+            for (child, _) in self.sftp.readdir(&remote_path)? {
+                if let Some(name) = child.file_name() {
+                    self.remove(&path.join(name))?;
+                }
+            }
+            self.sftp.rmdir(&remote_path)?;
+        } else {
+            self.sftp.unlink(&remote_path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove a local file or directory, recursively
+fn remove_local(path: &Path) -> io::Result<()> {
+    let result = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    };
+    result.map_err(context(path))
+}
+
+/// Copy up to `len` bytes
+fn copy_range<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let max = remaining.min(BUFFER_SIZE as u64) as usize;
+        let n = reader.read(&mut buffer[..max])?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::config::{SessionConfig, TransportKind};
+use crate::plan::Plan;
+use crate::shell::ShellCommand;
+use crate::state::SyncState;
+use crate::sync::{self, ChangeSet, SyncError};
+use crate::transport::Transport;
+
+use super::remote_exec::RemoteExecError;
+use super::Remote;
+
+/// The remote host over ssh.  Files are transferred either by running rsync,
+/// or over the sftp session of the remote.
+impl Transport for Remote {
+    fn push_dirsync_dir(
+        &mut self,
+        config: &SessionConfig,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        match config.transport {
+            TransportKind::Rsync => sync::sync_dirsync_dir(config),
+            TransportKind::Sftp => self
+                .sftp_transfer(config)
+                .and_then(|mut transfer| {
+                    transfer.push_dirsync_dir(state)?;
+                    Ok(transfer.into_plan())
+                })
+                .map_err(SyncError::Sftp),
+        }
+    }
+
+    fn push_changes(
+        &mut self,
+        config: &SessionConfig,
+        changes: &ChangeSet,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        match config.transport {
+            TransportKind::Rsync => sync::sync_changes(config, changes),
+            TransportKind::Sftp => self
+                .sftp_transfer(config)
+                .and_then(|mut transfer| {
+                    match changes.is_full() {
+                        true => transfer.push_all(state)?,
+                        false => transfer.push(changes.paths(), state)?,
+                    }
+                    Ok(transfer.into_plan())
+                })
+                .map_err(SyncError::Sftp),
+        }
+    }
+
+    fn push(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        match config.transport {
+            TransportKind::Rsync => sync::push(config, paths),
+            TransportKind::Sftp => self
+                .sftp_transfer(config)
+                .and_then(|mut transfer| {
+                    transfer.push(paths, state)?;
+                    Ok(transfer.into_plan())
+                })
+                .map_err(SyncError::Sftp),
+        }
+    }
+
+    fn pull(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+    ) -> Result<Plan, SyncError> {
+        match config.transport {
+            TransportKind::Rsync => sync::pull(config, paths),
+            TransportKind::Sftp => self
+                .sftp_transfer(config)
+                .and_then(|mut transfer| {
+                    transfer.pull(paths)?;
+                    Ok(transfer.into_plan())
+                })
+                .map_err(SyncError::Sftp),
+        }
+    }
+
+    fn exec(&mut self, command: &ShellCommand) -> Result<String, RemoteExecError> {
+        Remote::exec(self, command)
+    }
+
+    fn exec_stream(
+        &mut self,
+        command: &ShellCommand,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError> {
+        Remote::exec_stream(self, command, cancelled)
+    }
+
+    fn file_exists(&mut self, path: &Path) -> Result<bool, RemoteExecError> {
+        let command = ShellCommand::new("test")
+            .arg("-f")
+            .arg(path.to_string_lossy())
+            .raw("&& echo 1 || echo 0");
+        let s = Remote::exec(self, &command)?;
+        Ok(s.as_str() == "1\n")
+    }
+
+    fn mtime(&mut self, path: &Path) -> Option<u64> {
+        // sftp resolves relative paths against the home directory, like the shell
+        let root = self.root.strip_prefix("~").unwrap_or(&self.root);
+        let sftp = self.session.sftp().ok()?;
+        sftp.stat(&root.join(path)).ok()?.mtime
+    }
+
+    fn is_alive(&mut self) -> bool {
+        if let Some(keepalive) = &self.keepalive {
+            if keepalive.has_failed() {
+                return false;
+            }
+        }
+        matches!(self.try_exec(&ShellCommand::new("true")), Ok((_, 0)))
+    }
+
+    fn watch(self: Box<Self>, config: &SessionConfig, paths: &[String], on_path: &dyn Fn(&str)) {
+        (*self).watch_remote(config, paths, on_path)
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+/// Quote an argument for a POSIX shell
+pub fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Quote an argument, unless it only has characters which the shell takes literally
+fn quote_if_needed(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+:,./-".contains(c));
+    match plain {
+        true => arg.to_string(),
+        false => quote(arg),
+    }
+}
+
+/// Quote a path, leaving a leading `~` to be expanded to the home directory
+fn quote_path(path: &str) -> String {
+    if path == "~" {
+        return path.to_string();
+    }
+    match path.strip_prefix("~/") {
+        Some(path) => format!("~/{}", quote_if_needed(path)),
+        None => quote_if_needed(path),
+    }
+}
+
+/// A command line for a POSIX shell.
+///
+/// Arguments and paths are quoted as needed,
+/// so that they can't break the command or inject shell code.
+#[derive(Debug, Clone, Default)]
+pub struct ShellCommand {
+    words: Vec<String>,
+    dir: Option<String>,
+}
+
+impl ShellCommand {
+    pub fn new(program: &str) -> ShellCommand {
+        ShellCommand::default().arg(program)
+    }
+
+    /// A command line written by the user, which is passed to the shell as it is
+    pub fn script(script: &str) -> ShellCommand {
+        ShellCommand::default().raw(script)
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<str>) -> ShellCommand {
+        self.words.push(quote_if_needed(arg.as_ref()));
+        self
+    }
+
+    pub fn args<I>(self, args: I) -> ShellCommand
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        args.into_iter().fold(self, ShellCommand::arg)
+    }
+
+    /// Add a path argument, which may start with `~/`
+    pub fn path_arg(mut self, path: impl AsRef<str>) -> ShellCommand {
+        self.words.push(quote_path(path.as_ref()));
+        self
+    }
+
+    /// Add shell syntax as it is, e.g. an operator or a redirection
+    pub fn raw(mut self, syntax: &str) -> ShellCommand {
+        self.words.push(syntax.to_string());
+        self
+    }
+
+    /// Run the command in `dir`, which may start with `~/`
+    pub fn current_dir(mut self, dir: impl AsRef<str>) -> ShellCommand {
+        self.dir = Some(quote_path(dir.as_ref()));
+        self
+    }
+}
+
+impl fmt::Display for ShellCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(dir) = &self.dir {
+            write!(f, "cd {dir} && ")?;
+        }
+        write!(f, "{}", self.words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_if_needed("src/main.rs"), "src/main.rs");
+        assert_eq!(quote_if_needed("user@host:22"), "user@host:22");
+        assert_eq!(quote_if_needed(""), "''");
+        assert_eq!(quote_if_needed("a b"), "'a b'");
+        assert_eq!(quote_if_needed("$(rm -rf /)"), "'$(rm -rf /)'");
+        assert_eq!(quote_if_needed("it's"), r"'it'\''s'");
+        assert_eq!(quote_path("~/my dir"), "~/'my dir'");
+        assert_eq!(quote_path("~user"), "'~user'");
+        assert_eq!(
+            ShellCommand::new("ls")
+                .arg("a b")
+                .current_dir("~/my project")
+                .to_string(),
+            "cd ~/'my project' && ls 'a b'"
+        );
+    }
+}
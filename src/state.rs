@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::config::{SessionConfig, TransportKind};
+use crate::delta::{signature, Signature, MIN_DELTA_SIZE};
+use crate::ignore::IgnoreRules;
+
+/// The version of a file at the time it was last synced
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileVersion {
+    pub size: u64,
+    /// Modification time, in seconds since the epoch
+    pub mtime: u64,
+    #[serde(default)]
+    pub mtime_nanos: u32,
+    /// The SHA-256 of the contents, so it can be compared with the remote.
+    /// Empty in manifests written before content hashes were recorded.
+    #[serde(default)]
+    pub sha256: String,
+    /// Block checksums of large files, for delta transfers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+}
+
+/// The SHA-256 of a file's contents, as lowercase hex
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hash = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hash)?;
+    Ok(hash
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// The size and modification time of a regular file
+fn local_metadata(path: &Path) -> Option<(u64, u64, u32)> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    Some((metadata.len(), mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+impl FileVersion {
+    /// The current version of a local file, or `None` if it doesn't exist
+    pub fn of_local(path: &Path) -> Option<FileVersion> {
+        let (size, mtime, mtime_nanos) = local_metadata(path)?;
+        let sha256 = sha256_file(path).ok()?;
+        Some(FileVersion {
+            size,
+            mtime,
+            mtime_nanos,
+            sha256,
+            signature: None,
+        })
+    }
+
+    fn same_metadata(&self, path: &Path) -> bool {
+        local_metadata(path) == Some((self.size, self.mtime, self.mtime_nanos))
+    }
+
+    /// True if the local file still matches this version.
+    /// The contents are only hashed if the size matches but the mtime doesn't.
+    pub fn matches_local(&self, path: &Path) -> bool {
+        match local_metadata(path) {
+            None => false,
+            Some((size, _, _)) if size != self.size => false,
+            Some(_) => {
+                self.same_metadata(path) || sha256_file(path).ok().as_ref() == Some(&self.sha256)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("Failed to read sync state: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse sync state: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// How a local file differs from its last synced version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalChange {
+    Added,
+    Modified,
+    Deleted,
+}
+
+const STATE_PATH: &str = ".dirsync/state";
+
+/// The manifest of synced files, relative to the local root.
+///
+/// Each file has the version both hosts agreed on after the last sync, so
+/// that changes on either side can be detected by comparing against it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncState {
+    /// The remote the files were synced to
+    #[serde(default)]
+    pub destination: String,
+    pub files: BTreeMap<PathBuf, FileVersion>,
+    /// Whether block signatures are recorded for large files
+    #[serde(skip)]
+    signatures: bool,
+}
+
+impl SyncState {
+    pub fn path(local_root: &str) -> PathBuf {
+        Path::new(local_root).join(STATE_PATH)
+    }
+
+    /// True for the state file (or its temporary file), relative to the root
+    pub fn is_state_path(path: &Path) -> bool {
+        path == Path::new(STATE_PATH) || path == Path::new(STATE_PATH).with_extension("tmp")
+    }
+
+    /// Load the state, or an empty state if nothing has been synced
+    /// to the configured remote yet
+    pub fn load(config: &SessionConfig) -> Result<SyncState, StateError> {
+        let mut state: SyncState = match fs::read_to_string(SyncState::path(&config.local_root)) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => SyncState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        if state.destination != config.destination() {
+            state.destination = config.destination();
+            state.files.clear();
+        }
+        // files recorded without a content hash are compared again on both sides
+        state.files.retain(|_, version| !version.sha256.is_empty());
+        state.signatures = config.transport == TransportKind::Sftp;
+        Ok(state)
+    }
+
+    pub fn save(&self, local_root: &str) -> Result<(), StateError> {
+        let path = SyncState::path(local_root);
+        // write to a temporary file first, so the state is never left half-written
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Forget everything which was synced, e.g. after the remote was cleaned
+    pub fn remove(local_root: &str) -> Result<(), StateError> {
+        match fs::remove_file(SyncState::path(local_root)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&FileVersion> {
+        self.files.get(path)
+    }
+
+    /// The synced files at or below `path`
+    pub fn files_below<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+        self.files.keys().filter(move |file| file.starts_with(path))
+    }
+
+    /// The local changes since the last sync
+    pub fn local_changes(
+        &self,
+        local_root: &str,
+        ignore: &IgnoreRules,
+    ) -> BTreeMap<PathBuf, LocalChange> {
+        let root = Path::new(local_root);
+        let mut local_files = BTreeSet::new();
+        collect_local_files(root, Path::new(""), ignore, &mut local_files);
+
+        let mut changes = BTreeMap::new();
+        for path in &local_files {
+            match self.files.get(path) {
+                None => {
+                    changes.insert(path.clone(), LocalChange::Added);
+                }
+                Some(version) if !version.matches_local(&root.join(path)) => {
+                    changes.insert(path.clone(), LocalChange::Modified);
+                }
+                Some(_) => {}
+            }
+        }
+        for path in self.files.keys() {
+            // files which are ignored now are not deleted by the sync
+            if !local_files.contains(path) && !ignore.is_ignored(path, false) {
+                changes.insert(path.clone(), LocalChange::Deleted);
+            }
+        }
+        changes
+    }
+
+    /// Record the current local versions of the files at or below the given paths,
+    /// after they have been pushed.  Files which no longer exist or are ignored are forgotten.
+    pub fn record_local<'a>(
+        &mut self,
+        local_root: &str,
+        paths: impl Iterator<Item = &'a PathBuf>,
+        ignore: &IgnoreRules,
+    ) {
+        let root = Path::new(local_root);
+        let mut files = BTreeSet::new();
+        for path in paths {
+            collect_local_files(root, path, ignore, &mut files);
+            files.extend(self.files_below(path).cloned());
+        }
+        for file in files {
+            if is_internal(&file) {
+                continue;
+            }
+            if ignore.is_ignored(&file, false) {
+                self.files.remove(&file);
+                continue;
+            }
+            let local_file = root.join(&file);
+            // only files which were touched since the last sync are hashed again
+            if let Some(version) = self.files.get(&file) {
+                if version.same_metadata(&local_file) {
+                    continue;
+                }
+            }
+            match FileVersion::of_local(&local_file) {
+                Some(version) => self.insert(root, file, version),
+                None => {
+                    self.files.remove(&file);
+                }
+            }
+        }
+    }
+
+    /// Record the synced version of a file, with its block signature if it is large
+    pub fn insert(&mut self, local_root: &Path, path: PathBuf, mut version: FileVersion) {
+        if self.signatures && version.size >= MIN_DELTA_SIZE {
+            let local_file = local_root.join(&path);
+            version.signature = signature(&local_file)
+                .ok()
+                // a file changed while reading it would get a wrong signature
+                .filter(|_| version.same_metadata(&local_file));
+        }
+        self.files.insert(path, version);
+    }
+}
+
+/// Paths which belong to dirsync, and are never part of the manifest
+pub fn is_internal(path: &Path) -> bool {
+    path.starts_with(".dirsync")
+}
+
+/// Add the regular files at or below `path` (relative to the root) which are not ignored
+pub fn collect_local_files(
+    root: &Path,
+    path: &Path,
+    ignore: &IgnoreRules,
+    files: &mut BTreeSet<PathBuf>,
+) {
+    let Ok(metadata) = root.join(path).symlink_metadata() else {
+        return;
+    };
+    if ignore.is_ignored(path, metadata.is_dir()) {
+        return;
+    }
+    if metadata.is_file() {
+        if !is_internal(path) {
+            files.insert(path.to_path_buf());
+        }
+    } else if metadata.is_dir() {
+        let Ok(entries) = fs::read_dir(root.join(path)) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let child = path.join(entry.file_name());
+            if !is_internal(&child) {
+                collect_local_files(root, &child, ignore, files);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_hashes() {
+        let path = std::env::temp_dir().join(format!("dirsync-sha256-{}", std::process::id()));
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(sha256_file(&path).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use notify::DebouncedEvent;
+use thiserror::Error;
+
+use crate::config::{SessionConfig, SyncMode, LOCAL_SCHEME};
+use crate::ignore::IgnoreRules;
+use crate::plan::{Plan, PlannedChange};
+use crate::remote::remote_exec::RemoteExecError;
+use crate::transfer_set::{pull_rules, push_rules, TransferSet};
+use crate::watch::relative_path;
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("Failed to execute rsync: {0}")]
+    Spawn(std::io::Error),
+    #[error("rsync exited with status: {0}")]
+    Failed(String),
+    #[error("sftp transfer failed: {0}")]
+    Sftp(std::io::Error),
+    #[error("docker transfer failed: {0}")]
+    Docker(std::io::Error),
+    #[error(transparent)]
+    Exec(#[from] RemoteExecError),
+}
+
+/// The paths which changed since the last sync, relative to the local root
+#[derive(Debug, Default)]
+pub struct ChangeSet {
+    paths: BTreeSet<PathBuf>,
+    // set when the whole tree has to be synced, e.g. after the watcher lost events
+    full: bool,
+    // the number of paths left out since the last sync
+    skipped: usize,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.paths.is_empty()
+    }
+
+    /// True if the whole tree has to be synced
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// The number of event paths which were left out since the last sync
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn paths(&self) -> &BTreeSet<PathBuf> {
+        &self.paths
+    }
+
+    /// Add a path relative to the root, e.g. one reported by the remote watcher
+    pub fn insert(&mut self, path: PathBuf) {
+        self.paths.insert(path);
+    }
+
+    /// Request a sync of the whole tree
+    pub fn set_full(&mut self) {
+        self.full = true;
+    }
+
+    /// Add the changes of another change set, e.g. one which failed to sync
+    pub fn merge(&mut self, other: ChangeSet) {
+        self.paths.extend(other.paths);
+        self.full |= other.full;
+        self.skipped += other.skipped;
+    }
+
+    /// Add the paths affected by a watcher event.
+    /// Paths (relative to the root) for which `skip` returns true are left out.
+    pub fn add_event(&mut self, root: &Path, event: &DebouncedEvent, skip: &dyn Fn(&Path) -> bool) {
+        match event {
+            DebouncedEvent::Rescan => self.set_full(),
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => self.add_path(root, path, skip),
+            DebouncedEvent::Rename(from, to) => {
+                self.add_path(root, from, skip);
+                self.add_path(root, to, skip);
+            }
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Error(_, _) => {}
+        }
+    }
+
+    fn add_path(&mut self, root: &Path, path: &Path, skip: &dyn Fn(&Path) -> bool) {
+        match relative_path(root, path) {
+            Some(path) if path.as_os_str().is_empty() => self.set_full(),
+            Some(path) if skip(&path) => self.skipped += 1,
+            Some(path) => {
+                self.paths.insert(path);
+            }
+            None => eprintln!("ignoring path outside of the root: {}", path.display()),
+        }
+    }
+}
+
+fn rsync(
+    config: &SessionConfig,
+    source: &str,
+    destinatin: &str,
+    args: &Vec<String>,
+    files_from: Option<&BTreeSet<PathBuf>>,
+) -> Result<Plan, SyncError> {
+    println!("executing rsync: {} {}", source, destinatin);
+
+    let mut command = Command::new("rsync");
+    command
+        .arg("-v") // verbose output
+        .arg("-a") // archived: we use this to only sync files which have changed
+        .arg("-r") // recursive
+        .args(args);
+    if config.dry_run {
+        // only report what would change
+        command
+            .arg("--dry-run")
+            .arg("--itemize-changes")
+            .stdout(Stdio::piped());
+    }
+    if files_from.is_some() {
+        // only sync the listed paths, read null-separated from stdin
+        command
+            .arg("--files-from=-")
+            .arg("--from0")
+            .stdin(Stdio::piped());
+    }
+    let mut rsync = command
+        .arg(source)
+        .arg(destinatin)
+        .spawn()
+        .map_err(SyncError::Spawn)?;
+
+    let stdin = rsync.stdin.take();
+    let stdout = rsync.stdout.take();
+    let plan = thread::scope(|scope| {
+        // the paths are written from another thread, so that the output can be read meanwhile
+        let writer = scope.spawn(move || -> std::io::Result<()> {
+            let (Some(mut stdin), Some(paths)) = (stdin, files_from) else {
+                return Ok(());
+            };
+            for path in paths {
+                println!("  {}", path.display());
+                stdin.write_all(path.as_os_str().as_encoded_bytes())?;
+                stdin.write_all(b"\0")?;
+            }
+            Ok(())
+        });
+        let mut plan = Plan::default();
+        if let Some(stdout) = stdout {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Some((path, change)) = parse_itemized(&line) {
+                    plan.insert(path, change);
+                }
+            }
+        }
+        writer.join().unwrap().map(|_| plan)
+    })
+    .map_err(SyncError::Spawn)?;
+
+    let result = rsync.wait().map_err(SyncError::Spawn)?;
+
+    println!("rsync finished");
+    if !result.success() {
+        return Err(SyncError::Failed(result.to_string()));
+    }
+    Ok(plan)
+}
+
+/// Parse a line of `rsync --itemize-changes` output, e.g. `>f+++++++++ src/main.rs`
+fn parse_itemized(line: &str) -> Option<(PathBuf, PlannedChange)> {
+    let (item, path) = line.split_once(' ')?;
+    // symlinks are listed with their target
+    let path = path.trim_start().split(" -> ").next()?;
+    let path = PathBuf::from(path.trim_end_matches('/'));
+    if path.as_os_str().is_empty() || path == Path::new(".") {
+        return None;
+    }
+    if item == "*deleting" {
+        return Some((path, PlannedChange::Delete));
+    }
+    if item.len() < 9 {
+        return None;
+    }
+    match item.as_bytes()[0] {
+        b'<' | b'>' | b'c' if item[2..].starts_with('+') => Some((path, PlannedChange::Create)),
+        b'<' | b'>' => Some((path, PlannedChange::Update)),
+        // only the attributes would change
+        _ => None,
+    }
+}
+
+/// The arguments used for every rsync invocation
+fn shell_args(config: &SessionConfig) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    if config.local_target().is_some() {
+        return args;
+    }
+    if let Some(shell) = config.rsync_shell() {
+        args.push(String::from("-e"));
+        args.push(shell);
+    }
+    args
+}
+
+/// The arguments which exclude ignored paths from a sync of the given paths
+fn exclude_args<'a>(
+    config: &SessionConfig,
+    paths: impl IntoIterator<Item = &'a Path>,
+) -> Vec<String> {
+    IgnoreRules::new(config).rsync_filters(paths)
+}
+
+/// The remote directory which holds the dirsync client installed on the remote.
+/// It only exists on the remote, so it must never be deleted by a sync.
+pub const REMOTE_CLIENT_DIR: &str = ".dirsync/client";
+
+/// Whether deletions can be propagated to the remote at all.
+/// In bidirectional mode they always are, since a file deleted on one side
+/// would otherwise be restored from the other.
+pub fn deletes_enabled(config: &SessionConfig) -> bool {
+    if !config.delete && config.mode != SyncMode::Bidirectional {
+        return false;
+    }
+    let root = config.remote.root.trim();
+    let root = root.strip_prefix(LOCAL_SCHEME).unwrap_or(root);
+    if root.is_empty() || root == "/" || root == "~" || root == "~/" {
+        eprintln!("Warning: not propagating deletions to the remote root: {:?}", root);
+        return false;
+    }
+    true
+}
+
+/// A path may only be deleted on the remote if it is strictly inside remote.root,
+/// and not inside a receive path
+pub fn is_safe_to_delete(config: &SessionConfig, path: &Path) -> bool {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return false;
+    }
+    if path.starts_with(REMOTE_CLIENT_DIR) {
+        return false;
+    }
+    !config.is_in_receive_path(path)
+}
+
+/// Push the .dirsync directory.
+/// We sync actions explicitly, since they might be ignored otherwise
+pub fn sync_dirsync_dir(config: &SessionConfig) -> Result<Plan, SyncError> {
+    let dirsync_dir_local = &format!("{}/.dirsync", &config.local_root);
+    let dirsync_dir_remote = &config.destination().to_string();
+    rsync(
+        config,
+        dirsync_dir_local,
+        dirsync_dir_remote,
+        &shell_args(config),
+        None,
+    )
+}
+
+// Perform rsync of the whole tree from source to destination
+pub fn sync(config: &SessionConfig) -> Result<Plan, SyncError> {
+    let mut plan = sync_dirsync_dir(config)?;
+
+    let mut args = shell_args(config);
+    args.extend(exclude_args(config, [Path::new("")]));
+    if deletes_enabled(config) {
+        // excluded paths (including receive paths) are never deleted
+        args.push(String::from("--delete"));
+        args.push(format!("--filter=P /{REMOTE_CLIENT_DIR}"));
+    }
+    // sync the contents of the local root into the remote root, like the incremental sync
+    let source = format!("{}/", &config.local_root);
+    let destination = format!("{}/", config.destination());
+    plan.extend(rsync(config, &source, &destination, &args, None)?);
+    Ok(plan)
+}
+
+/// Sync only the paths in the change set, or the whole tree if it requires a full sync
+pub fn sync_changes(config: &SessionConfig, changes: &ChangeSet) -> Result<Plan, SyncError> {
+    if changes.full {
+        return sync(config);
+    }
+    push(config, &changes.paths)
+}
+
+/// Push the given paths to the remote.
+/// Paths which no longer exist locally are deleted on the remote, if enabled.
+pub fn push(config: &SessionConfig, paths: &BTreeSet<PathBuf>) -> Result<Plan, SyncError> {
+    let ignore = IgnoreRules::new(config);
+    let set = TransferSet::push(config, &ignore, paths);
+    // rsync doesn't apply the excludes of parent directories to listed paths,
+    // so only the paths of the set are listed
+    let (dirsync_paths, paths): (BTreeSet<PathBuf>, BTreeSet<PathBuf>) = set
+        .roots()
+        .into_iter()
+        .partition(|path| push_rules(&ignore, path).is_none());
+
+    let source = format!("{}/", &config.local_root);
+    let destination = format!("{}/", config.destination());
+    let mut delete_args: Vec<String> = Vec::new();
+    if !set.deleted.is_empty() {
+        delete_args.push(String::from("--delete-missing-args"));
+        delete_args.push(String::from("--force"));
+    }
+
+    let mut plan = Plan::default();
+    // the .dirsync directory is pushed without filters
+    if !dirsync_paths.is_empty() {
+        let mut args = shell_args(config);
+        args.extend(delete_args.clone());
+        plan.extend(rsync(
+            config,
+            &source,
+            &destination,
+            &args,
+            Some(&dirsync_paths),
+        )?);
+    }
+    if !paths.is_empty() {
+        let mut args = shell_args(config);
+        args.extend(ignore.rsync_filters(paths.iter().map(PathBuf::as_path)));
+        args.extend(delete_args);
+        plan.extend(rsync(config, &source, &destination, &args, Some(&paths))?);
+    }
+    Ok(plan)
+}
+
+/// Pull the given paths from the remote into the local root.
+/// Paths which no longer exist on the remote are deleted locally.
+pub fn pull(config: &SessionConfig, paths: &BTreeSet<PathBuf>) -> Result<Plan, SyncError> {
+    let source = format!("{}/", config.destination());
+    let destination = format!("{}/", &config.local_root);
+    let mut args = shell_args(config);
+    if let Some(ignore) = pull_rules(config) {
+        args.extend(ignore.rsync_filters(paths.iter().map(PathBuf::as_path)));
+    }
+    args.push(String::from("--delete-missing-args"));
+    args.push(String::from("--force"));
+    rsync(config, &source, &destination, &args, Some(paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn itemized_changes() {
+        assert_eq!(
+            parse_itemized(">f+++++++++ src/main.rs"),
+            Some((PathBuf::from("src/main.rs"), PlannedChange::Create))
+        );
+        assert_eq!(
+            parse_itemized(">f.st...... README.md"),
+            Some((PathBuf::from("README.md"), PlannedChange::Update))
+        );
+        assert_eq!(
+            parse_itemized("cd+++++++++ src/"),
+            Some((PathBuf::from("src"), PlannedChange::Create))
+        );
+        assert_eq!(
+            parse_itemized("cL+++++++++ link -> target"),
+            Some((PathBuf::from("link"), PlannedChange::Create))
+        );
+        assert_eq!(
+            parse_itemized("*deleting   old/file.txt"),
+            Some((PathBuf::from("old/file.txt"), PlannedChange::Delete))
+        );
+        // only the attributes change
+        assert_eq!(parse_itemized(".d..t...... src/"), None);
+        assert_eq!(parse_itemized("cd+++++++++ ./"), None);
+        assert_eq!(parse_itemized("sending incremental file list"), None);
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{SessionConfig, SyncMode};
+use crate::ignore::IgnoreRules;
+use crate::sync::{deletes_enabled, is_safe_to_delete};
+
+/// The paths a transfer copies and deletes, relative to the roots.
+/// The transports only carry these out, so that all of them apply
+/// the same ignore, delete and .dirsync rules.
+#[derive(Debug, Default)]
+pub struct TransferSet {
+    /// The paths to copy, and whether they are directories.
+    /// Directories come before their contents.
+    pub copied: BTreeMap<PathBuf, bool>,
+    /// The paths to delete at the destination, leaving out the contents of deleted directories
+    pub deleted: Vec<PathBuf>,
+}
+
+/// The rules for pushing a path.
+/// The .dirsync directory is synced without excludes, since it might be ignored otherwise.
+pub fn push_rules<'a>(ignore: &'a IgnoreRules, path: &Path) -> Option<&'a IgnoreRules> {
+    match path.starts_with(".dirsync") {
+        true => None,
+        false => Some(ignore),
+    }
+}
+
+/// The rules for pulling paths.
+/// Receive paths are pulled even if they are ignored, everything else is not.
+pub fn pull_rules(config: &SessionConfig) -> Option<IgnoreRules> {
+    match config.mode {
+        SyncMode::Bidirectional => Some(IgnoreRules::new(config)),
+        SyncMode::Push => None,
+    }
+}
+
+/// True if a path which doesn't exist anymore is ignored.
+/// Its type is unknown, so it counts as ignored if it would be as either a file or a directory.
+fn was_ignored(ignore: Option<&IgnoreRules>, path: &Path) -> bool {
+    ignore.is_some_and(|ignore| ignore.is_ignored(path, false) || ignore.is_ignored(path, true))
+}
+
+impl TransferSet {
+    /// Push the .dirsync directory
+    pub fn dirsync_dir(config: &SessionConfig) -> TransferSet {
+        let mut set = TransferSet::default();
+        set.add_local(Path::new(&config.local_root), Path::new(".dirsync"), None);
+        set
+    }
+
+    /// Push the whole tree, except for the .dirsync directory which is pushed separately.
+    /// `remote` has the paths below the remote root and whether they are directories;
+    /// those which don't exist locally are deleted, if enabled.
+    pub fn push_all(
+        config: &SessionConfig,
+        ignore: &IgnoreRules,
+        remote: &BTreeMap<PathBuf, bool>,
+    ) -> TransferSet {
+        let mut set = TransferSet::default();
+        set.add_local(Path::new(&config.local_root), Path::new(""), Some(ignore));
+        if !deletes_enabled(config) {
+            return set;
+        }
+        for (path, is_dir) in remote {
+            if path.starts_with(".dirsync") || set.copied.contains_key(path) || set.is_deleted(path)
+            {
+                continue;
+            }
+            if is_safe_to_delete(config, path) && !ignore.is_ignored(path, *is_dir) {
+                set.deleted.push(path.clone());
+            }
+        }
+        set
+    }
+
+    /// Push the given paths.
+    /// Paths which no longer exist locally are deleted on the remote, if enabled.
+    pub fn push(
+        config: &SessionConfig,
+        ignore: &IgnoreRules,
+        paths: &BTreeSet<PathBuf>,
+    ) -> TransferSet {
+        let local_root = Path::new(&config.local_root);
+        let deletes = deletes_enabled(config);
+        let mut set = TransferSet::default();
+        for path in paths {
+            let ignore = push_rules(ignore, path);
+            if local_root.join(path).symlink_metadata().is_ok() {
+                set.add_local(local_root, path, ignore);
+            } else if deletes
+                && is_safe_to_delete(config, path)
+                && !was_ignored(ignore, path)
+                && !set.is_deleted(path)
+            {
+                set.deleted.push(path.clone());
+            }
+        }
+        set
+    }
+
+    /// Pull the given paths.
+    /// `remote` has the paths at or below them which exist on the remote,
+    /// and whether they are directories.
+    /// Paths which no longer exist on the remote are deleted locally.
+    pub fn pull(
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+        remote: &BTreeMap<PathBuf, bool>,
+    ) -> TransferSet {
+        let ignore = pull_rules(config);
+        let mut set = TransferSet::default();
+        for (path, is_dir) in remote {
+            if !ignore
+                .as_ref()
+                .is_some_and(|ignore| ignore.is_ignored(path, *is_dir))
+            {
+                set.copied.insert(path.clone(), *is_dir);
+            }
+        }
+        for path in paths {
+            if !remote.contains_key(path)
+                && !was_ignored(ignore.as_ref(), path)
+                && !set.is_deleted(path)
+            {
+                set.deleted.push(path.clone());
+            }
+        }
+        set
+    }
+
+    /// The copied and deleted paths which aren't inside another one,
+    /// for transfers which copy directories recursively
+    pub fn roots(&self) -> BTreeSet<PathBuf> {
+        let mut roots = BTreeSet::new();
+        let mut last: Option<&Path> = None;
+        for path in self.copied.keys() {
+            if last.is_some_and(|parent| path.starts_with(parent)) {
+                continue;
+            }
+            roots.insert(path.clone());
+            last = Some(path);
+        }
+        roots.extend(self.deleted.iter().cloned());
+        roots
+    }
+
+    /// True if the path is inside a directory which is already deleted
+    fn is_deleted(&self, path: &Path) -> bool {
+        self.deleted
+            .last()
+            .is_some_and(|parent| path.starts_with(parent))
+    }
+
+    /// Add the local paths at or below `path`, leaving out ignored paths.
+    /// The .dirsync directory is only included if it is requested explicitly.
+    fn add_local(&mut self, root: &Path, path: &Path, ignore: Option<&IgnoreRules>) {
+        let Ok(metadata) = root.join(path).symlink_metadata() else {
+            return;
+        };
+        if ignore.is_some_and(|ignore| ignore.is_ignored(path, metadata.is_dir())) {
+            return;
+        }
+        if !path.as_os_str().is_empty() {
+            self.copied.insert(path.to_path_buf(), metadata.is_dir());
+        }
+        if !metadata.is_dir() {
+            return;
+        }
+        let Ok(children) = fs::read_dir(root.join(path)) else {
+            return;
+        };
+        for child in children.flatten() {
+            let child = path.join(child.file_name());
+            if child.starts_with(".dirsync") && !path.starts_with(".dirsync") {
+                continue;
+            }
+            self.add_local(root, &child, ignore);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local root with the given files and config settings
+    fn session_config(name: &str, settings: &str, files: &[(&str, &str)]) -> SessionConfig {
+        let root = std::env::temp_dir().join(format!(
+            "dirsync-transfer-set-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join(".dirsync")).unwrap();
+        let config = format!("{settings}\n[remote]\nroot = \"local:///srv/app\"\n");
+        fs::write(root.join(".dirsync/config.toml"), config).unwrap();
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        SessionConfig::with_local_root(&root.to_string_lossy()).unwrap()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn listing(entries: &[(&str, bool)]) -> BTreeMap<PathBuf, bool> {
+        entries
+            .iter()
+            .map(|(path, is_dir)| (PathBuf::from(path), *is_dir))
+            .collect()
+    }
+
+    fn copied(set: &TransferSet) -> Vec<PathBuf> {
+        set.copied.keys().cloned().collect()
+    }
+
+    #[test]
+    fn full_push_keeps_ignored_remote_directories() {
+        let config = session_config(
+            "full",
+            "delete = true",
+            &[
+                (".dirsync/ignore", "target/"),
+                ("a.txt", "a"),
+                ("sub/b.txt", "b"),
+                ("target/x", "x"),
+                (".git/HEAD", "ref"),
+            ],
+        );
+        let remote = listing(&[
+            (".dirsync", true),
+            (".dirsync/client", true),
+            (".git", true),
+            (".git/HEAD", false),
+            ("a.txt", false),
+            ("gone", true),
+            ("gone/f", false),
+            ("old.txt", false),
+            ("target", true),
+            ("target/debug", true),
+            ("target/debug/bin", false),
+        ]);
+        let set = TransferSet::push_all(&config, &IgnoreRules::new(&config), &remote);
+        assert_eq!(copied(&set), paths(&["a.txt", "sub", "sub/b.txt"]));
+        assert_eq!(set.deleted, paths(&["gone", "old.txt"]));
+        let _ = fs::remove_dir_all(&config.local_root);
+    }
+
+    #[test]
+    fn push_of_changed_paths() {
+        let config = session_config(
+            "changed",
+            "delete = true",
+            &[
+                (".dirsync/ignore", "target/\n*.tmp"),
+                (".dirsync/a.tmp", "a"),
+                ("sub/b.txt", "b"),
+                ("x.tmp", "x"),
+            ],
+        );
+        let changed = paths(&[".dirsync/a.tmp", "gone", "gone/f", "sub", "target", "x.tmp"]);
+        let set = TransferSet::push(
+            &config,
+            &IgnoreRules::new(&config),
+            &changed.into_iter().collect(),
+        );
+        // the .dirsync directory is pushed even if it is ignored
+        assert_eq!(copied(&set), paths(&[".dirsync/a.tmp", "sub", "sub/b.txt"]));
+        // a deleted path which might have been an ignored directory is kept
+        assert_eq!(set.deleted, paths(&["gone"]));
+        assert_eq!(
+            set.roots(),
+            paths(&[".dirsync/a.tmp", "gone", "sub"])
+                .into_iter()
+                .collect()
+        );
+        let _ = fs::remove_dir_all(&config.local_root);
+    }
+
+    #[test]
+    fn push_without_deletes() {
+        let config = session_config("no-deletes", "", &[("a.txt", "a")]);
+        let changed = paths(&["a.txt", "gone.txt"]);
+        let set = TransferSet::push(
+            &config,
+            &IgnoreRules::new(&config),
+            &changed.into_iter().collect(),
+        );
+        assert_eq!(copied(&set), paths(&["a.txt"]));
+        assert!(set.deleted.is_empty());
+        let _ = fs::remove_dir_all(&config.local_root);
+    }
+
+    #[test]
+    fn pulls() {
+        let remote = listing(&[("out", true), ("out/r.txt", false), ("out/target", true)]);
+        let requested: BTreeSet<PathBuf> =
+            paths(&["gone.txt", "out", "target"]).into_iter().collect();
+
+        // receive paths are pulled whole
+        let config = session_config("pull", "", &[(".dirsync/ignore", "target/")]);
+        let set = TransferSet::pull(&config, &requested, &remote);
+        assert_eq!(copied(&set), paths(&["out", "out/r.txt", "out/target"]));
+        assert_eq!(set.deleted, paths(&["gone.txt", "target"]));
+        let _ = fs::remove_dir_all(&config.local_root);
+
+        let config = session_config(
+            "pull-bidirectional",
+            "mode = \"bidirectional\"",
+            &[(".dirsync/ignore", "target/")],
+        );
+        let set = TransferSet::pull(&config, &requested, &remote);
+        assert_eq!(copied(&set), paths(&["out", "out/r.txt"]));
+        assert_eq!(set.deleted, paths(&["gone.txt"]));
+        let _ = fs::remove_dir_all(&config.local_root);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use crate::config::{SessionConfig, USAGE_ERROR};
+use crate::ignore::IgnoreRules;
+use crate::plan::{Plan, PlannedChange};
+use crate::remote::receive_from_remote::strip_root;
+use crate::remote::remote_exec::RemoteExecError;
+use crate::remote::RemoteConnectError;
+use crate::shell::ShellCommand;
+use crate::state::SyncState;
+use crate::sync::{deletes_enabled, ChangeSet, SyncError};
+use crate::transfer_set::TransferSet;
+use crate::watch::FileEvent;
+
+use super::{wait_or_cancel, Transport};
+
+/// A directory in a running docker container.
+/// Files are copied as tar streams through `docker cp`, and commands run with `docker exec`.
+pub struct DockerTransport {
+    container: String,
+    root: String,
+    // set in a dry run, when the root doesn't exist yet
+    missing_root: bool,
+}
+
+/// Check that the container is running
+fn check_running(container: &str) -> Result<(), String> {
+    let output = Command::new("docker")
+        .args(["inspect", "-f", "{{.State.Running}}", container])
+        .output()
+        .map_err(|err| format!("failed to run docker: {err}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "true" => Ok(()),
+        _ => Err(String::from("the container is not running")),
+    }
+}
+
+fn wait(child: &mut Child, name: &str) -> io::Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{name} exited with status: {status}"
+        )));
+    }
+    Ok(())
+}
+
+impl DockerTransport {
+    pub fn open(config: &SessionConfig) -> Result<DockerTransport, RemoteConnectError> {
+        let container = config.remote.container.clone().unwrap_or_default();
+        // `docker cp` resolves relative paths against /, and `docker exec` against the working directory
+        if !config.remote.root.starts_with('/') {
+            return Err(RemoteConnectError::Docker(
+                container,
+                format!("the root must be an absolute path: {}", config.remote.root),
+            ));
+        }
+        check_running(&container)
+            .map_err(|err| RemoteConnectError::Docker(container.clone(), err))?;
+
+        let mut transport = DockerTransport {
+            container,
+            root: config.remote.root.clone(),
+            missing_root: false,
+        };
+        // a dry run treats a missing root as empty, instead of creating it
+        let command = match config.dry_run {
+            true => ["test", "-d"],
+            // `docker cp` needs the destination directory to exist
+            false => ["mkdir", "-p"],
+        };
+        let status = Command::new("docker")
+            .args(["exec", &transport.container])
+            .args(command)
+            .arg(&transport.root)
+            .status()
+            .map_err(|err| {
+                RemoteConnectError::Docker(transport.container.clone(), err.to_string())
+            })?;
+        if config.dry_run {
+            transport.missing_root = !status.success();
+        } else if !status.success() {
+            return Err(RemoteConnectError::Docker(
+                transport.container.clone(),
+                format!("failed to create {}", transport.root),
+            ));
+        }
+        Ok(transport)
+    }
+
+    /// A shell command run in the root, inside the container
+    fn docker_exec(&self, command: &ShellCommand) -> Command {
+        let mut docker = Command::new("docker");
+        docker
+            .arg("exec")
+            .arg("-i")
+            .arg(&self.container)
+            .arg("sh")
+            .arg("-c")
+            .arg(command.clone().current_dir(&self.root).to_string());
+        docker
+    }
+
+    /// Copy local paths into the root, as a tar stream through `docker cp`
+    fn copy_in(&self, local_root: &str, paths: &BTreeMap<PathBuf, bool>) -> io::Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        // directories are listed explicitly, so that ignored paths below them are left out
+        let mut tar = Command::new("tar")
+            .args([
+                "-c",
+                "-f",
+                "-",
+                "--no-recursion",
+                "-C",
+                local_root,
+                "--null",
+                "-T",
+                "-",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut docker = Command::new("docker")
+            .arg("cp")
+            .arg("-")
+            .arg(format!("{}:{}", self.container, self.root))
+            .stdin(tar.stdout.take().unwrap())
+            .spawn()?;
+
+        let mut stdin = tar.stdin.take().unwrap();
+        for path in paths.keys() {
+            println!("  {}", path.display());
+            stdin.write_all(path.as_os_str().as_encoded_bytes())?;
+            stdin.write_all(b"\0")?;
+        }
+        drop(stdin);
+        wait(&mut tar, "tar")?;
+        wait(&mut docker, "docker cp")
+    }
+
+    /// Copy a path from the root into the local root
+    fn copy_out(&self, local_root: &str, path: &Path) -> io::Result<()> {
+        let local_parent = Path::new(local_root).join(path.parent().unwrap_or(Path::new("")));
+        fs::create_dir_all(&local_parent)?;
+        let mut docker = Command::new("docker")
+            .arg("cp")
+            .arg(format!(
+                "{}:{}/{}",
+                self.container,
+                self.root,
+                path.display()
+            ))
+            .arg("-")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut tar = Command::new("tar")
+            .args(["-x", "-f", "-", "-C"])
+            .arg(&local_parent)
+            .stdin(docker.stdout.take().unwrap())
+            .spawn()?;
+        wait(&mut docker, "docker cp")?;
+        wait(&mut tar, "tar")
+    }
+
+    /// Remove paths below the root
+    fn remove(&self, paths: &[PathBuf]) -> Result<(), RemoteExecError> {
+        for chunk in paths.chunks(100) {
+            let paths = chunk.iter().map(|path| {
+                println!("deleting {}", path.display());
+                path.to_string_lossy()
+            });
+            self.exec(&ShellCommand::new("rm").args(["-rf", "--"]).args(paths))?;
+        }
+        Ok(())
+    }
+
+    /// The paths below the root, except for the .dirsync directory
+    fn remote_paths(&self) -> Result<BTreeMap<PathBuf, bool>, RemoteExecError> {
+        self.find(
+            &[String::from(".")],
+            &["-path", "./.dirsync", "-prune", "-o"],
+        )
+    }
+
+    /// The paths at or below the given ones (relative to the root) which `find` prints
+    /// after the given expression, and whether they are directories.
+    /// Paths which don't exist are left out.
+    fn find(
+        &self,
+        paths: &[String],
+        expression: &[&str],
+    ) -> Result<BTreeMap<PathBuf, bool>, RemoteExecError> {
+        let mut found = BTreeMap::new();
+        // `-printf` is GNU only, so directories and other files are listed separately
+        for (test, is_dir) in [
+            (&["-type", "d"][..], true),
+            (&["!", "-type", "d"][..], false),
+        ] {
+            let command = ShellCommand::new("for")
+                .args(["path", "in"])
+                .args(paths)
+                .raw("; do if [ -e \"$path\" ] || [ -L \"$path\" ]; then find \"$path\"")
+                .args(expression)
+                .args(test)
+                .raw("-print || exit 1; fi; done");
+            let output = self.exec(&command)?;
+            found.extend(
+                output
+                    .lines()
+                    .filter_map(|path| Some((PathBuf::from(path.strip_prefix("./")?), is_dir))),
+            );
+        }
+        Ok(found)
+    }
+
+    /// What copying in and removing the paths of the set would change, for a dry run
+    fn plan(&self, set: &TransferSet) -> Result<Plan, RemoteExecError> {
+        let existing = self.find(&[String::from(".")], &[])?;
+        let mut plan = Plan::default();
+        if self.missing_root {
+            plan.create_root(&self.root);
+        }
+        for (path, is_dir) in &set.copied {
+            match existing.get(path) {
+                None => plan.insert(path.clone(), PlannedChange::Create),
+                Some(_) if !is_dir => plan.insert(path.clone(), PlannedChange::Update),
+                Some(_) => {}
+            }
+        }
+        for path in &set.deleted {
+            if existing.contains_key(path) {
+                plan.insert(path.clone(), PlannedChange::Delete);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Run `dirsync watch` in the root, and call `on_path` with each changed path.
+    /// Returns true if the client rejected the watcher options.
+    fn run_watch(&self, watch_args: &[String], paths: &[String], on_path: &dyn Fn(&str)) -> bool {
+        // dirsync has to be installed in the container
+        let command = ShellCommand::new("dirsync")
+            .args(["watch", "-r", "."])
+            .args(watch_args)
+            .args(paths);
+        let mut watch = match self.docker_exec(&command).stdout(Stdio::piped()).spawn() {
+            Ok(watch) => watch,
+            Err(err) => {
+                eprintln!("Error watching the container: {err}");
+                return false;
+            }
+        };
+        let stdout = watch.stdout.take().unwrap();
+        let roots = [PathBuf::from(&self.root)];
+        let mut changed = false;
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match serde_json::from_str::<FileEvent>(line.trim()) {
+                Ok(event) => {
+                    changed = true;
+                    for path in event.paths() {
+                        on_path(&strip_root(&roots, path));
+                    }
+                }
+                Err(_) => println!("Read line from container: {}", line.trim_end()),
+            }
+        }
+        let code = match watch.wait() {
+            Ok(status) if status.success() => return false,
+            Ok(status) => status.code(),
+            Err(_) => None,
+        };
+        if !changed && !watch_args.is_empty() && code == Some(USAGE_ERROR) {
+            return true;
+        }
+        eprintln!("Watching the container failed, is dirsync installed in it?");
+        false
+    }
+
+    fn exec(&self, command: &ShellCommand) -> Result<String, RemoteExecError> {
+        println!("exec: {command}");
+        if self.missing_root {
+            return Ok(String::new());
+        }
+        let output = self
+            .docker_exec(command)
+            .output()
+            .map_err(|err| RemoteExecError::ExecError(command.to_string(), err.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(RemoteExecError::ExecError(
+                command.to_string(),
+                format!("{}: {}", output.status, stderr.trim_end()),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+impl Transport for DockerTransport {
+    fn push_dirsync_dir(
+        &mut self,
+        config: &SessionConfig,
+        _state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        let set = TransferSet::dirsync_dir(config);
+        if config.dry_run {
+            return Ok(self.plan(&set)?);
+        }
+        self.copy_in(&config.local_root, &set.copied)
+            .map_err(SyncError::Docker)?;
+        Ok(Plan::default())
+    }
+
+    fn push_changes(
+        &mut self,
+        config: &SessionConfig,
+        changes: &ChangeSet,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        if !changes.is_full() {
+            return self.push(config, changes.paths(), state);
+        }
+        let mut plan = self.push_dirsync_dir(config, state)?;
+
+        let ignore = IgnoreRules::new(config);
+        let remote = match deletes_enabled(config) {
+            true => self.remote_paths()?,
+            false => BTreeMap::new(),
+        };
+        let set = TransferSet::push_all(config, &ignore, &remote);
+        if config.dry_run {
+            plan.extend(self.plan(&set)?);
+            return Ok(plan);
+        }
+        self.copy_in(&config.local_root, &set.copied)
+            .map_err(SyncError::Docker)?;
+        self.remove(&set.deleted)?;
+        Ok(plan)
+    }
+
+    fn push(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+        _state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        let set = TransferSet::push(config, &IgnoreRules::new(config), paths);
+        if config.dry_run {
+            return Ok(self.plan(&set)?);
+        }
+        self.copy_in(&config.local_root, &set.copied)
+            .map_err(SyncError::Docker)?;
+        self.remove(&set.deleted)?;
+        Ok(Plan::default())
+    }
+
+    fn pull(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+    ) -> Result<Plan, SyncError> {
+        if self.missing_root {
+            return Ok(Plan::default());
+        }
+        let paths_in_root: Vec<String> = paths
+            .iter()
+            .map(|path| format!("./{}", path.display()))
+            .collect();
+        let remote = self.find(&paths_in_root, &[])?;
+        let set = TransferSet::pull(config, paths, &remote);
+        let local_root = Path::new(&config.local_root);
+        let mut plan = Plan::default();
+        for (path, is_dir) in &set.copied {
+            let local_path = local_root.join(path);
+            if config.dry_run {
+                match local_path.symlink_metadata() {
+                    Err(_) => plan.insert(path.clone(), PlannedChange::Create),
+                    Ok(_) if !is_dir => plan.insert(path.clone(), PlannedChange::Update),
+                    Ok(_) => {}
+                }
+                continue;
+            }
+            println!("  {}", path.display());
+            match is_dir {
+                true => fs::create_dir_all(&local_path),
+                false => self.copy_out(&config.local_root, path),
+            }
+            .map_err(SyncError::Docker)?;
+        }
+        for path in &set.deleted {
+            let local_path = local_root.join(path);
+            let Ok(metadata) = local_path.symlink_metadata() else {
+                continue;
+            };
+            if config.dry_run {
+                plan.insert(path.clone(), PlannedChange::Delete);
+                continue;
+            }
+            println!("deleting {}", path.display());
+            let removed = match metadata.is_dir() {
+                true => fs::remove_dir_all(&local_path),
+                false => fs::remove_file(&local_path),
+            };
+            removed.map_err(SyncError::Docker)?;
+        }
+        Ok(plan)
+    }
+
+    fn exec(&mut self, command: &ShellCommand) -> Result<String, RemoteExecError> {
+        DockerTransport::exec(self, command)
+    }
+
+    fn exec_stream(
+        &mut self,
+        command: &ShellCommand,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError> {
+        let mut child = self
+            .docker_exec(command)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|err| RemoteExecError::ExecError(command.to_string(), err.to_string()))?;
+        wait_or_cancel(&mut child, command, cancelled)
+    }
+
+    fn file_exists(&mut self, path: &Path) -> Result<bool, RemoteExecError> {
+        let command = ShellCommand::new("test")
+            .arg("-f")
+            .arg(path.to_string_lossy())
+            .raw("&& echo 1 || echo 0");
+        Ok(DockerTransport::exec(self, &command)? == "1\n")
+    }
+
+    fn mtime(&mut self, path: &Path) -> Option<u64> {
+        let command = ShellCommand::new("stat")
+            .args(["-c", "%Y"])
+            .arg(path.to_string_lossy());
+        let output = DockerTransport::exec(self, &command).ok()?;
+        output.trim().parse().ok()
+    }
+
+    fn is_alive(&mut self) -> bool {
+        check_running(&self.container).is_ok()
+    }
+
+    fn watch(self: Box<Self>, config: &SessionConfig, paths: &[String], on_path: &dyn Fn(&str)) {
+        // an older client rejects the watcher options, so it is run again without them
+        if self.run_watch(&config.watch_args(), paths, on_path) {
+            eprintln!(
+                "Warning: the dirsync client in the container can't poll, using its native watcher"
+            );
+            self.run_watch(&[], paths, on_path);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use crate::config::SessionConfig;
+use crate::plan::Plan;
+use crate::remote::remote_exec::RemoteExecError;
+use crate::remote::RemoteConnectError;
+use crate::shell::ShellCommand;
+use crate::state::SyncState;
+use crate::sync::{self, ChangeSet, SyncError};
+use crate::watch::watch_events;
+
+use super::{wait_or_cancel, Transport};
+
+/// A target directory on this machine, e.g. a second disk or a mounted volume.
+/// Files are transferred with a local rsync, and actions run in a local shell.
+pub struct LocalTransport {
+    root: PathBuf,
+    // set in a dry run, when the root doesn't exist yet
+    missing_root: bool,
+}
+
+impl LocalTransport {
+    /// Open the target directory, creating it if its parent exists.
+    /// A dry run doesn't create it, and treats it as empty.
+    pub fn open(root: PathBuf, dry_run: bool) -> Result<LocalTransport, RemoteConnectError> {
+        let open_error = |err| RemoteConnectError::Local(root.display().to_string(), err);
+        // an unmounted volume shouldn't be recreated on the mount point's disk
+        match root.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+                return Err(open_error(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} does not exist", parent.display()),
+                )));
+            }
+            _ => {}
+        }
+        let missing_root = dry_run && !root.exists();
+        if !dry_run {
+            fs::create_dir_all(&root).map_err(open_error)?;
+        }
+        Ok(LocalTransport { root, missing_root })
+    }
+
+    /// Add the creation of the root to the plan of a dry run, if it doesn't exist yet
+    fn with_root(&self, mut plan: Plan) -> Plan {
+        if self.missing_root {
+            plan.create_root(&self.root.to_string_lossy());
+        }
+        plan
+    }
+
+    fn shell(&self, command: &ShellCommand) -> Command {
+        let mut shell = Command::new("sh");
+        shell
+            .arg("-c")
+            .arg(command.to_string())
+            .current_dir(&self.root);
+        shell
+    }
+}
+
+impl Transport for LocalTransport {
+    fn push_dirsync_dir(
+        &mut self,
+        config: &SessionConfig,
+        _state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        Ok(self.with_root(sync::sync_dirsync_dir(config)?))
+    }
+
+    fn push_changes(
+        &mut self,
+        config: &SessionConfig,
+        changes: &ChangeSet,
+        _state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        Ok(self.with_root(sync::sync_changes(config, changes)?))
+    }
+
+    fn push(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+        _state: &SyncState,
+    ) -> Result<Plan, SyncError> {
+        Ok(self.with_root(sync::push(config, paths)?))
+    }
+
+    fn pull(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+    ) -> Result<Plan, SyncError> {
+        if self.missing_root {
+            return Ok(Plan::default());
+        }
+        sync::pull(config, paths)
+    }
+
+    fn delete(&mut self, path: &Path) -> Result<(), RemoteExecError> {
+        let target = self.root.join(path);
+        let result = match target.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target),
+            Ok(_) => fs::remove_file(&target),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        };
+        result.map_err(|err| {
+            RemoteExecError::ExecError(format!("delete {}", target.display()), err.to_string())
+        })
+    }
+
+    fn exec(&mut self, command: &ShellCommand) -> Result<String, RemoteExecError> {
+        println!("exec: {command}");
+        if self.missing_root {
+            return Ok(String::new());
+        }
+        let output = self
+            .shell(command)
+            .output()
+            .map_err(|err| RemoteExecError::ExecError(command.to_string(), err.to_string()))?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn exec_stream(
+        &mut self,
+        command: &ShellCommand,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError> {
+        let mut child = self
+            .shell(command)
+            .spawn()
+            .map_err(|err| RemoteExecError::ExecError(command.to_string(), err.to_string()))?;
+        wait_or_cancel(&mut child, command, cancelled)
+    }
+
+    fn file_exists(&mut self, path: &Path) -> Result<bool, RemoteExecError> {
+        Ok(self.root.join(path).is_file())
+    }
+
+    fn mtime(&mut self, path: &Path) -> Option<u64> {
+        let mtime = self.root.join(path).metadata().ok()?.modified().ok()?;
+        Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_secs())
+    }
+
+    fn is_alive(&mut self) -> bool {
+        self.root.is_dir()
+    }
+
+    fn watch(self: Box<Self>, config: &SessionConfig, paths: &[String], on_path: &dyn Fn(&str)) {
+        let (watcher, poll_interval) = (config.watcher, config.poll_interval);
+        watch_events(&self.root, paths, watcher, poll_interval, |event| {
+            for path in event.paths() {
+                on_path(path);
+            }
+        })
+    }
+}
@@ -0,0 +1,302 @@
+pub mod docker;
+pub mod local;
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{SessionConfig, TargetKind};
+use crate::plan::Plan;
+use crate::remote::remote_exec::RemoteExecError;
+use crate::remote::{Remote, RemoteConnectError};
+use crate::shell::ShellCommand;
+use crate::state::SyncState;
+use crate::sync::{ChangeSet, SyncError};
+use docker::DockerTransport;
+use local::LocalTransport;
+
+/// The other end of the sync: where files are pushed to and pulled from,
+/// and where actions are run.
+///
+/// Paths are relative to the root of the target, and commands run in it.
+/// In a dry run, the transfers change nothing and return the plan of what they would do.
+pub trait Transport: Send {
+    /// Push the .dirsync directory, which is never ignored
+    fn push_dirsync_dir(
+        &mut self,
+        config: &SessionConfig,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError>;
+
+    /// Push the paths in the change set, or the whole tree if it requires a full sync
+    fn push_changes(
+        &mut self,
+        config: &SessionConfig,
+        changes: &ChangeSet,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError>;
+
+    /// Push the given paths.
+    /// Paths which no longer exist locally are deleted on the target, if enabled.
+    fn push(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+        state: &SyncState,
+    ) -> Result<Plan, SyncError>;
+
+    /// Pull the given paths into the local root.
+    /// Paths which no longer exist on the target are deleted locally.
+    fn pull(
+        &mut self,
+        config: &SessionConfig,
+        paths: &BTreeSet<PathBuf>,
+    ) -> Result<Plan, SyncError>;
+
+    /// Delete a path recursively.  An empty path deletes the root itself.
+    fn delete(&mut self, path: &Path) -> Result<(), RemoteExecError> {
+        let command = ShellCommand::new("rm").args(["-rf", "--"]);
+        // the root as the shell resolves it, which is also what `clean` asks to confirm
+        let command = match path.as_os_str().is_empty() {
+            true => command.raw("\"$PWD\""),
+            false => command.arg(path.to_string_lossy()),
+        };
+        self.exec_checked(&command)
+    }
+
+    /// Run a shell command, and return its output
+    fn exec(&mut self, command: &ShellCommand) -> Result<String, RemoteExecError>;
+
+    /// Run a shell command, and fail unless it exits successfully
+    fn exec_checked(&mut self, command: &ShellCommand) -> Result<(), RemoteExecError> {
+        let output = self.exec(&command.clone().raw("2>&1 && echo ok || echo failed"))?;
+        match output.lines().last() {
+            Some("ok") => Ok(()),
+            _ => {
+                let message = output.trim_end().trim_end_matches("failed").trim_end();
+                Err(RemoteExecError::ExecError(
+                    command.to_string(),
+                    message.to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Run a shell command, streaming its output to stdout,
+    /// until it exits or `cancelled` returns true
+    fn exec_stream(
+        &mut self,
+        command: &ShellCommand,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError>;
+
+    fn file_exists(&mut self, path: &Path) -> Result<bool, RemoteExecError>;
+
+    /// The modification time of a file, in seconds since the epoch
+    fn mtime(&mut self, path: &Path) -> Option<u64>;
+
+    /// Check whether the target is still reachable
+    fn is_alive(&mut self) -> bool;
+
+    /// Watch the given paths on the target, and call `on_path` with each changed path.
+    /// Blocks until the watch stops, e.g. because the connection was lost.
+    fn watch(self: Box<Self>, config: &SessionConfig, paths: &[String], on_path: &dyn Fn(&str));
+
+    /// The SHA-256 of the regular files at or below the given paths.
+    /// Files which don't exist on the target are left out.
+    fn file_versions(
+        &mut self,
+        paths: &[PathBuf],
+    ) -> Result<HashMap<PathBuf, String>, RemoteExecError> {
+        let mut versions = HashMap::new();
+        // keep the command lines well below the argument limit
+        for chunk in paths.chunks(100) {
+            let paths = chunk.iter().map(|path| match path.as_os_str().is_empty() {
+                true => String::from("."),
+                // a leading ./ keeps find from reading paths as options
+                false => format!("./{}", path.to_string_lossy()),
+            });
+            let command = ShellCommand::new("find")
+                .args(paths)
+                .args(["(", "-name", ".git", "-o", "-path", "./.dirsync", ")"])
+                .args(["-prune", "-o", "-type", "f"])
+                .args(["-exec", "sha256sum", "{}", "+"])
+                .raw("2>/dev/null; true");
+            let output = self.exec(&command)?;
+            versions.extend(output.lines().filter_map(parse_sha256sum));
+        }
+        Ok(versions)
+    }
+
+    /// All paths below the root, except for the .dirsync directory
+    fn list_paths(&mut self) -> Result<BTreeSet<PathBuf>, RemoteExecError> {
+        let command = ShellCommand::new("find")
+            .args([".", "-mindepth", "1"])
+            .args(["-path", "./.dirsync", "-prune", "-o", "-print"]);
+        let output = self.exec(&command)?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.strip_prefix("./"))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Delete files, and the directories which are left empty
+    fn remove_files(&mut self, paths: &[PathBuf]) -> Result<(), RemoteExecError> {
+        let names = |paths: &[&Path]| {
+            paths
+                .iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+        };
+        let files: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        // keep the command lines well below the argument limit
+        for chunk in files.chunks(100) {
+            self.exec_checked(
+                &ShellCommand::new("rm")
+                    .args(["-f", "--"])
+                    .args(names(chunk)),
+            )?;
+        }
+        let dirs: BTreeSet<&Path> = paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        let dirs: Vec<&Path> = dirs.into_iter().collect();
+        for chunk in dirs.chunks(100) {
+            // directories which still have other files in them are kept
+            self.exec(
+                &ShellCommand::new("rmdir")
+                    .args(["-p", "--"])
+                    .args(names(chunk))
+                    .raw("2>/dev/null; true"),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Run the action for an event (`.dirsync/actions/<event>/remote`), if there is one
+    fn execute_if_exists(
+        &mut self,
+        event: &str,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), RemoteExecError> {
+        let path = Path::new(".dirsync/actions").join(event).join("remote");
+        if !self.file_exists(&path)? {
+            println!("file does not exist: {}", path.display());
+            return Ok(());
+        }
+
+        let path = path.to_string_lossy();
+        self.exec(&ShellCommand::new("chmod").arg("+x").arg(&path))?;
+        self.exec_stream(&ShellCommand::new(&path), cancelled)
+    }
+}
+
+/// How often a running action checks whether it has been cancelled
+pub const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Wait for a local process running `command` to exit, or kill it once `cancelled` returns true.
+/// Fails unless the process exits successfully.
+pub fn wait_or_cancel(
+    child: &mut Child,
+    command: &ShellCommand,
+    cancelled: &dyn Fn() -> bool,
+) -> Result<(), RemoteExecError> {
+    let exec_error =
+        |err: std::io::Error| RemoteExecError::ExecError(command.to_string(), err.to_string());
+    loop {
+        if let Some(status) = child.try_wait().map_err(exec_error)? {
+            return match status.success() {
+                true => Ok(()),
+                false => Err(RemoteExecError::ExecError(
+                    command.to_string(),
+                    status.to_string(),
+                )),
+            };
+        }
+        if cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(RemoteExecError::Cancelled(command.to_string()));
+        }
+        thread::sleep(CANCEL_POLL_INTERVAL);
+    }
+}
+
+/// Parse a line of `sha256sum` output: `<hash>  <path>`, or `<hash> *<path>` in binary mode.
+/// Names with a backslash or a line break are escaped, and the line starts with a backslash.
+fn parse_sha256sum(line: &str) -> Option<(PathBuf, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, path) = line.split_once(' ')?;
+    if hash.len() != 64 {
+        return None;
+    }
+    let path = path.get(1..)?;
+    let path = path.strip_prefix("./").unwrap_or(path);
+    if !escaped {
+        return Some((PathBuf::from(path), hash.to_string()));
+    }
+    let mut unescaped = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.push(match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            }),
+            c => unescaped.push(c),
+        }
+    }
+    Some((PathBuf::from(unescaped), hash.to_string()))
+}
+
+/// Connect to the configured target
+pub fn connect(config: &SessionConfig) -> Result<Box<dyn Transport>, RemoteConnectError> {
+    match (config.target_kind(), config.local_target()) {
+        (TargetKind::Local, Some(root)) => Ok(Box::new(LocalTransport::open(root, config.dry_run)?)),
+        (TargetKind::Docker, _) => Ok(Box::new(DockerTransport::open(config)?)),
+        _ => Ok(Box::new(Remote::connect(config)?)),
+    }
+}
+
+/// Connect to the target, or report the error and exit
+pub fn connect_or_exit(config: &SessionConfig) -> Box<dyn Transport> {
+    match connect(config) {
+        Ok(transport) => transport,
+        Err(err) => {
+            eprintln!("Error connecting to the remote: {err}");
+            exit(err.exit_code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256sum_output() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let parse = |line: String| parse_sha256sum(&line).map(|(path, _)| path);
+        assert_eq!(
+            parse_sha256sum(&format!("{hash}  ./src/main.rs")),
+            Some((PathBuf::from("src/main.rs"), hash.to_string()))
+        );
+        assert_eq!(parse(format!("{hash} *./a b")), Some(PathBuf::from("a b")));
+        assert_eq!(
+            parse(format!("\\{hash}  ./back\\\\slash\\nline")),
+            Some(PathBuf::from("back\\slash\nline"))
+        );
+        assert_eq!(parse(format!("\\{hash}  ./bad\\x")), None);
+        assert_eq!(parse(String::from("sha256sum: ./gone: No such file")), None);
+    }
+}
@@ -1,8 +1,22 @@
-use std::{sync::mpsc::channel, time::Duration};
+use std::{
+    env, io,
+    path::{self, Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
 
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use notify::{watcher, DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
 
+use crate::config::WatcherKind;
+
+// the errors inotify reports when the watch or instance limit is reached
+const ENOSPC: i32 = 28;
+const EMFILE: i32 = 24;
+
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum EventType {
     Create,
@@ -12,6 +26,7 @@ pub enum EventType {
     Rename,
 }
 
+/// A file event emitted by `dirsync watch`, with paths relative to the watch root
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FileEvent {
     Create(String),
@@ -21,26 +36,251 @@ pub enum FileEvent {
     Rename(String, String),
 }
 
-pub fn watch_paths(root: &String, relative_paths: &Vec<String>) {
-    let (tx, rx) = channel();
-    let mut watcher = watcher(tx, Duration::from_millis(20)).unwrap();
+impl FileEvent {
+    /// The paths affected by the event
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            FileEvent::Create(path)
+            | FileEvent::Write(path)
+            | FileEvent::Chmod(path)
+            | FileEvent::Remove(path) => vec![path],
+            FileEvent::Rename(from, to) => vec![from, to],
+        }
+    }
+}
+
+/// Groups bursts of watcher events into batches.
+/// A batch ends once no event arrived for the quiet period,
+/// or after the maximum latency since its first event while events keep coming.
+pub struct Debouncer {
+    quiet: Duration,
+    max_latency: Duration,
+    // the times of the first and last event of the current batch
+    batch: Option<(Instant, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration, max_latency: Duration) -> Debouncer {
+        Debouncer {
+            quiet,
+            max_latency,
+            batch: None,
+        }
+    }
+
+    /// Add an event to the current batch, or start a new one
+    pub fn event(&mut self) {
+        let now = Instant::now();
+        let first = self.batch.map_or(now, |(first, _)| first);
+        self.batch = Some((first, now));
+    }
+
+    /// The time left until the current batch ends, or None if there is no batch
+    pub fn remaining(&self) -> Option<Duration> {
+        let (first, last) = self.batch?;
+        let end = (last + self.quiet).min(first + self.max_latency);
+        Some(end.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn finish(&mut self) {
+        self.batch = None;
+    }
+}
+
+/// A running file watcher, which stops watching when it is dropped
+pub struct FileWatcher {
+    // the native watcher is replaced when it falls back to polling
+    _watching: Arc<Mutex<Watching>>,
+}
+
+// the watchers are only held to keep them running
+#[allow(dead_code)]
+enum Watching {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl FileWatcher {
+    /// Watch the given paths recursively, and send their events to `tx`.
+    /// The native watcher falls back to polling if it runs into the inotify limits,
+    /// at the start or when watching a new directory.
+    pub fn start(
+        kind: WatcherKind,
+        poll_interval: Duration,
+        paths: &[PathBuf],
+        tx: Sender<DebouncedEvent>,
+    ) -> notify::Result<FileWatcher> {
+        // the poll watcher reports paths the way they were given, and events need absolute ones
+        let paths = paths
+            .iter()
+            .map(path::absolute)
+            .collect::<io::Result<Vec<PathBuf>>>()
+            .map_err(notify::Error::Io)?;
+        if kind == WatcherKind::Poll {
+            let poll = start_polling(&paths, poll_interval, tx)?;
+            return Ok(FileWatcher {
+                _watching: Arc::new(Mutex::new(Watching::Poll(poll))),
+            });
+        }
+
+        // the events pass through another channel, so that new directories can be checked
+        let (native_tx, native_rx) = channel();
+        let native = watcher(native_tx, Duration::from_millis(20)).and_then(|mut native| {
+            for path in &paths {
+                native.watch(path, RecursiveMode::Recursive)?;
+            }
+            Ok(native)
+        });
+        let watching = match native {
+            Ok(native) => Arc::new(Mutex::new(Watching::Native(native))),
+            Err(err) => {
+                let Some(limit) = inotify_limit(&err) else {
+                    return Err(err);
+                };
+                warn_inotify_limit(limit, poll_interval);
+                let poll = start_polling(&paths, poll_interval, tx)?;
+                return Ok(FileWatcher {
+                    _watching: Arc::new(Mutex::new(Watching::Poll(poll))),
+                });
+            }
+        };
+        let weak = Arc::downgrade(&watching);
+        thread::spawn(move || forward_native(native_rx, tx, weak, &paths, poll_interval));
+        Ok(FileWatcher {
+            _watching: watching,
+        })
+    }
+}
+
+fn start_polling(
+    paths: &[PathBuf],
+    poll_interval: Duration,
+    tx: Sender<DebouncedEvent>,
+) -> notify::Result<PollWatcher> {
+    let mut poll = PollWatcher::new(tx, poll_interval)?;
+    for path in paths {
+        poll.watch(path, RecursiveMode::Recursive)?;
+    }
+    Ok(poll)
+}
+
+/// Forward the events of the native watcher to `tx`, until it is dropped.
+/// notify ignores the errors of watching new directories, so they are watched again here,
+/// and the watcher falls back to polling once that runs into the inotify limits.
+fn forward_native(
+    rx: Receiver<DebouncedEvent>,
+    tx: Sender<DebouncedEvent>,
+    watching: Weak<Mutex<Watching>>,
+    paths: &[PathBuf],
+    poll_interval: Duration,
+) {
+    for event in rx {
+        let Some(watching) = watching.upgrade() else {
+            return;
+        };
+        let mut watching = watching.lock().unwrap();
+        if let (DebouncedEvent::Create(dir), Watching::Native(native)) = (&event, &mut *watching) {
+            let limit = match dir.is_dir() {
+                true => native.watch(dir, RecursiveMode::Recursive).err(),
+                false => None,
+            };
+            if let Some(limit) = limit.as_ref().and_then(inotify_limit) {
+                warn_inotify_limit(limit, poll_interval);
+                match start_polling(paths, poll_interval, tx.clone()) {
+                    Ok(poll) => {
+                        *watching = Watching::Poll(poll);
+                        // changes in the unwatched directories may have been missed
+                        let _ = tx.send(DebouncedEvent::Rescan);
+                    }
+                    Err(err) => eprintln!("Failed to start polling for changes: {err}"),
+                }
+            }
+        }
+        if tx.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+fn warn_inotify_limit((setting, value): (&str, u32), poll_interval: Duration) {
+    eprintln!(
+        "Warning: the inotify limit was reached, polling for changes every {}ms instead",
+        poll_interval.as_millis()
+    );
+    eprintln!("To raise the limit, run: sudo sysctl fs.inotify.{setting}={value}");
+}
+
+/// The sysctl setting and a suggested value, if the error means an inotify limit was reached
+fn inotify_limit(err: &notify::Error) -> Option<(&'static str, u32)> {
+    let notify::Error::Io(err) = err else {
+        return None;
+    };
+    match err.raw_os_error() {
+        Some(ENOSPC) => Some(("max_user_watches", 524288)),
+        Some(EMFILE) => Some(("max_user_instances", 1024)),
+        _ => None,
+    }
+}
 
-    for path in relative_paths {
-        watcher
-            .watch(format!("{}/{}", root, path), RecursiveMode::Recursive)
-            .unwrap();
+/// The paths affected by a local watcher event
+pub fn event_paths(event: &DebouncedEvent) -> Vec<&Path> {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => vec![path],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        _ => vec![],
     }
+}
+
+/// The path of a watcher event relative to the watch root.
+/// Watchers report absolute paths, which may or may not be canonicalized.
+pub fn relative_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    let absolute_root = env::current_dir().ok()?.join(root);
+    if let Ok(relative) = path.strip_prefix(&absolute_root) {
+        return Some(relative.to_path_buf());
+    }
+    let canonical_root = absolute_root.canonicalize().ok()?;
+    path.strip_prefix(canonical_root)
+        .ok()
+        .map(|relative| relative.to_path_buf())
+}
+
+pub fn watch_paths(
+    root: &String,
+    relative_paths: &[String],
+    kind: WatcherKind,
+    poll_interval: Duration,
+) {
+    let print = |event| {
+        if let Ok(json) = serde_json::to_string(&event) {
+            println!("{json}");
+        }
+    };
+    watch_events(Path::new(root), relative_paths, kind, poll_interval, print);
+}
+
+/// Watch paths relative to the root, and call `on_event` with each event.
+/// Never returns.
+pub fn watch_events(
+    root: &Path,
+    relative_paths: &[String],
+    kind: WatcherKind,
+    poll_interval: Duration,
+    mut on_event: impl FnMut(FileEvent),
+) {
+    let (tx, rx) = channel();
+    let paths: Vec<PathBuf> = relative_paths.iter().map(|path| root.join(path)).collect();
+    let _watcher = FileWatcher::start(kind, poll_interval, &paths, tx).unwrap();
 
     loop {
         match rx.recv() {
             Ok(event) => {
-                println!("ehandling event: {:?}", event);
-                match map(event) {
-                    Some(event) => {
-                        if let Ok(json) = serde_json::to_string(&event) {
-                            println!("{json}");
-                        }
-                    }
+                // stdout is reserved for the events themselves
+                eprintln!("handling event: {:?}", event);
+                match map(root, event) {
+                    Some(event) => on_event(event),
                     None => eprintln!("ignoring event"),
                 };
             }
@@ -49,19 +289,49 @@ pub fn watch_paths(root: &String, relative_paths: &Vec<String>) {
     }
 }
 
-fn map(event: DebouncedEvent) -> Option<FileEvent> {
+fn map(root: &Path, event: DebouncedEvent) -> Option<FileEvent> {
+    let relative = |path: PathBuf| {
+        relative_path(root, &path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    };
     match event {
         DebouncedEvent::NoticeWrite(_) => None,
         DebouncedEvent::NoticeRemove(_) => None,
         DebouncedEvent::Rescan => None,
         DebouncedEvent::Error(_, _) => None,
-        DebouncedEvent::Create(path) => Some(FileEvent::Create(path.to_string_lossy().to_string())),
-        DebouncedEvent::Write(path) => Some(FileEvent::Write(path.to_string_lossy().to_string())),
-        DebouncedEvent::Chmod(path) => Some(FileEvent::Chmod(path.to_string_lossy().to_string())),
-        DebouncedEvent::Remove(path) => Some(FileEvent::Remove(path.to_string_lossy().to_string())),
-        DebouncedEvent::Rename(from, to) => Some(FileEvent::Rename(
-            from.to_string_lossy().to_string(),
-            to.to_string_lossy().to_string(),
-        )),
+        DebouncedEvent::Create(path) => Some(FileEvent::Create(relative(path))),
+        DebouncedEvent::Write(path) => Some(FileEvent::Write(relative(path))),
+        DebouncedEvent::Chmod(path) => Some(FileEvent::Chmod(relative(path))),
+        DebouncedEvent::Remove(path) => Some(FileEvent::Remove(relative(path))),
+        DebouncedEvent::Rename(from, to) => Some(FileEvent::Rename(relative(from), relative(to))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_period() {
+        let mut debouncer = Debouncer::new(Duration::from_millis(100), Duration::from_secs(10));
+        assert_eq!(debouncer.remaining(), None);
+        debouncer.event();
+        let remaining = debouncer.remaining().unwrap();
+        assert!(remaining <= Duration::from_millis(100));
+        assert!(remaining > Duration::from_millis(50));
+        debouncer.finish();
+        assert_eq!(debouncer.remaining(), None);
+    }
+
+    #[test]
+    fn max_latency() {
+        let mut debouncer = Debouncer::new(Duration::from_secs(10), Duration::from_millis(100));
+        debouncer.event();
+        std::thread::sleep(Duration::from_millis(60));
+        // later events don't extend the batch beyond the maximum latency
+        debouncer.event();
+        assert!(debouncer.remaining().unwrap() <= Duration::from_millis(40));
     }
 }
@@ -0,0 +1,205 @@
+//! End-to-end tests of sync sessions with a `local://` target, run through the dirsync binary.
+//! The local target transfers files with rsync, so they are ignored by default;
+//! run them with `cargo test -- --ignored` where rsync is installed.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A local root and a target directory, with a dirsync session between them
+struct Session {
+    dir: PathBuf,
+    child: Option<Child>,
+}
+
+impl Session {
+    /// A session with the given top-level and `[remote]` settings
+    fn new(name: &str, settings: &str, remote: &str) -> Session {
+        let dir = std::env::temp_dir().join(format!("dirsync-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("local/.dirsync")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        let config = format!(
+            "{settings}\n[remote]\nroot = \"local://{}\"\n{remote}\n",
+            dir.join("target").display()
+        );
+        fs::write(dir.join("local/.dirsync/config.toml"), config).unwrap();
+        Session { dir, child: None }
+    }
+
+    fn local(&self, path: &str) -> PathBuf {
+        self.dir.join("local").join(path)
+    }
+
+    fn target(&self, path: &str) -> PathBuf {
+        self.dir.join("target").join(path)
+    }
+
+    /// Start dirsync, and wait until `probe` is synced from `from` to `to`,
+    /// so that the watchers are running
+    fn start(
+        &mut self,
+        probe: &str,
+        from: fn(&Session, &str) -> PathBuf,
+        to: fn(&Session, &str) -> PathBuf,
+    ) {
+        let log = File::create(self.dir.join("log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_dirsync"))
+            .arg(self.local(""))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+
+        // the watchers start after the initial sync, which might copy the probe as well,
+        // so it is rewritten until a second version arrives
+        let start = Instant::now();
+        let mut arrived = Vec::new();
+        let mut n = 0;
+        while arrived.len() < 2 {
+            self.check_timeout(start, "the watchers to start");
+            let contents = read(&to(self, probe));
+            if !contents.is_empty() && !arrived.contains(&contents) {
+                arrived.push(contents);
+            }
+            n += 1;
+            write(&from(self, probe), &n.to_string());
+            sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Wait until the condition holds
+    fn wait_for(&self, what: &str, condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            self.check_timeout(start, what);
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn check_timeout(&self, start: Instant, what: &str) {
+        if start.elapsed() > TIMEOUT {
+            let log = fs::read_to_string(self.dir.join("log")).unwrap_or_default();
+            panic!("timed out waiting for {what}, dirsync output:\n{log}");
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+#[test]
+#[ignore = "requires rsync"]
+fn pushes_changes_and_deletions() {
+    let mut session = Session::new("push", "delete = true", "");
+    write(&session.local(".dirsync/ignore"), "build/\n");
+    write(&session.local("a.txt"), "one");
+    write(&session.local("sub/b.txt"), "b");
+    write(&session.local("build/out"), "local build");
+    write(&session.target("build/out"), "remote build");
+    write(&session.target("stale.txt"), "stale");
+
+    session.start("probe", Session::local, Session::target);
+    assert_eq!(read(&session.target("a.txt")), "one");
+    assert_eq!(read(&session.target("sub/b.txt")), "b");
+    // the initial sync deletes what doesn't exist locally, except for ignored paths
+    assert!(!session.target("stale.txt").exists());
+    assert_eq!(read(&session.target("build/out")), "remote build");
+
+    write(&session.local("a.txt"), "two");
+    session.wait_for("a.txt to be updated", || {
+        read(&session.target("a.txt")) == "two"
+    });
+
+    fs::remove_dir_all(session.local("sub")).unwrap();
+    session.wait_for("sub to be deleted", || !session.target("sub").exists());
+    assert_eq!(read(&session.target("build/out")), "remote build");
+}
+
+#[test]
+#[ignore = "requires rsync"]
+fn keeps_remote_files_without_delete() {
+    let mut session = Session::new("no-delete", "", "");
+    write(&session.local("a.txt"), "a");
+    write(&session.target("remote.txt"), "remote");
+
+    session.start("probe", Session::local, Session::target);
+    fs::remove_file(session.local("a.txt")).unwrap();
+    write(&session.local("b.txt"), "b");
+    session.wait_for("b.txt to be pushed", || session.target("b.txt").exists());
+    assert!(session.target("a.txt").exists());
+    assert!(session.target("remote.txt").exists());
+}
+
+#[test]
+#[ignore = "requires rsync"]
+fn pulls_receive_paths() {
+    let remote = "[[remote.receive_paths]]\npath = \"out\"";
+    let mut session = Session::new("receive", "", remote);
+    write(&session.local("a.txt"), "a");
+
+    session.start("out/probe", Session::target, Session::local);
+    write(&session.target("out/result.txt"), "result");
+    session.wait_for("out/result.txt to be pulled", || {
+        read(&session.local("out/result.txt")) == "result"
+    });
+
+    fs::remove_file(session.target("out/result.txt")).unwrap();
+    session.wait_for("out/result.txt to be deleted", || {
+        !session.local("out/result.txt").exists()
+    });
+    // receive paths are never pushed
+    write(&session.local("out/local.txt"), "local");
+    write(&session.local("b.txt"), "b");
+    session.wait_for("b.txt to be pushed", || session.target("b.txt").exists());
+    assert!(!session.target("out/local.txt").exists());
+}
+
+#[test]
+#[ignore = "requires rsync"]
+fn reconciles_both_sides_in_bidirectional_mode() {
+    let mut session = Session::new("bidirectional", "mode = \"bidirectional\"", "");
+    write(&session.local("local.txt"), "local");
+    write(&session.target("remote.txt"), "remote");
+
+    session.start("probe", Session::target, Session::local);
+    // the probe might be pulled before the startup reconciliation pushes
+    session.wait_for("local.txt to be pushed", || {
+        read(&session.target("local.txt")) == "local"
+    });
+    assert_eq!(read(&session.local("remote.txt")), "remote");
+    // changes to the paths the startup sync transferred are echoes for a while
+    sleep(Duration::from_secs(2));
+
+    write(&session.target("remote.txt"), "changed remotely");
+    session.wait_for("remote.txt to be pulled", || {
+        read(&session.local("remote.txt")) == "changed remotely"
+    });
+
+    fs::remove_file(session.target("local.txt")).unwrap();
+    session.wait_for("local.txt to be deleted locally", || {
+        !session.local("local.txt").exists()
+    });
+}